@@ -0,0 +1,133 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Extension of files that are still being written. Anything carrying it is
+/// incomplete and never a valid output.
+pub const PARTIAL_EXTENSION: &str = "partial";
+
+static NEXT_PARTIAL: AtomicU64 = AtomicU64::new(0);
+
+/// A file written under a unique temporary name next to its destination and
+/// only renamed into place by `commit`. Dropped without committing, it removes
+/// itself, so a crash or an error never leaves a truncated file at the
+/// destination.
+pub struct PartialFile {
+    path: PathBuf,
+    destination: PathBuf,
+    committed: bool,
+}
+
+impl PartialFile {
+    pub fn new(destination: &Path) -> Self {
+        Self {
+            path: partial_path(destination),
+            destination: destination.to_path_buf(),
+            committed: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Flushes the file to disk and atomically renames it to its destination.
+    pub fn commit(mut self) -> io::Result<()> {
+        File::open(&self.path)?.sync_all()?;
+        fs::rename(&self.path, &self.destination)?;
+        self.committed = true;
+        sync_parent_dir(&self.destination)
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Copies `source` to `destination` without `destination` ever being
+/// observable half-written, e.g. when moving across filesystems.
+pub fn copy_atomically(source: &Path, destination: &Path) -> io::Result<()> {
+    let partial = PartialFile::new(destination);
+    fs::copy(source, partial.path())?;
+    partial.commit()
+}
+
+/// `<destination>.<pid>-<nanos>-<n>.partial`, unique across threads and
+/// replicas sharing a volume.
+fn partial_path(destination: &Path) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let n = NEXT_PARTIAL.fetch_add(1, Ordering::Relaxed);
+
+    let mut name = destination.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".{}-{nanos}-{n}.{PARTIAL_EXTENSION}",
+        std::process::id()
+    ));
+    destination.with_file_name(name)
+}
+
+/// Makes a rename durable. Directories cannot be opened for syncing on every
+/// platform, so failures to open one are ignored.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) else {
+        return Ok(());
+    };
+    match File::open(parent) {
+        Ok(dir) => dir.sync_all().or(Ok(())),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PARTIAL_EXTENSION, PartialFile};
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn unique_temp_dir(name: &str) -> std::path::PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should be monotonic")
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("worker-atomic-{name}-{nanos}"))
+    }
+
+    #[test]
+    fn only_committed_files_reach_their_destination() {
+        let dir = unique_temp_dir("commit");
+        fs::create_dir_all(&dir).expect("dir should be created");
+        let destination = dir.join("out.wav");
+
+        let abandoned = PartialFile::new(&destination);
+        fs::write(abandoned.path(), b"half").expect("partial should be written");
+        assert!(
+            abandoned
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == PARTIAL_EXTENSION)
+        );
+        drop(abandoned);
+        assert_eq!(fs::read_dir(&dir).expect("dir should list").count(), 0);
+
+        let partial = PartialFile::new(&destination);
+        fs::write(partial.path(), b"whole").expect("partial should be written");
+        partial.commit().expect("partial should be committed");
+        assert_eq!(
+            fs::read(&destination).expect("output should exist"),
+            b"whole"
+        );
+        assert_eq!(fs::read_dir(&dir).expect("dir should list").count(), 1);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
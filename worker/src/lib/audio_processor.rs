@@ -1,43 +1,127 @@
-use hound::{WavSpec, WavWriter};
+use hound::{WavReader, WavSpec, WavWriter};
 use indicatif::{ProgressBar, ProgressStyle};
-use std::io::Cursor;
-use std::path::Path;
-use symphonia::core::codecs::CODEC_TYPE_NULL;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, Decoder};
 use symphonia::core::errors::Error;
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::formats::{Packet, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
 use symphonia::default::get_probe;
 
-use crate::lib::effects::{AudioEffect, EffectConfig};
+use crate::lib::atomic_file::PartialFile;
+use crate::lib::broadcast::Bext;
+use crate::lib::cancellation::{CancelToken, JobCancelled};
+use crate::lib::effects::{AudioEffect, AudioJob};
+use crate::lib::error::WorkerError;
+use crate::lib::limits::JobLimits;
+use crate::lib::markers::{CuePoint, MarkerDetector, cue_chunks, parse_cues};
+use crate::lib::media_source::{InputSources, open_media_source};
+use crate::lib::riff::{Chunk, HeadCapture, append_chunks, read_wav_chunks};
+use crate::lib::spec_conversion::SpecConverter;
+use crate::lib::status::ProgressSender;
+use crate::lib::tags::{ArtworkInfo, MediaTags};
 
+/// What was written for one rendered track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioSummary {
+    /// Position of the track among the input's audio tracks.
+    pub track_index: usize,
+    pub track_id: u32,
+    pub language: Option<String>,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub frames: u64,
+    pub sha256: [u8; 32],
+    /// How often the input switched sample rate or channel count midway.
+    /// Those parts were converted to the spec the output started with.
+    pub spec_changes: u32,
+    /// Tags of the input, before the job's edits.
+    pub tags: BTreeMap<String, String>,
+    pub artwork: Option<ArtworkInfo>,
+    /// Markers written to the output, in position order.
+    pub markers: Vec<CuePoint>,
+}
+
+impl AudioSummary {
+    pub fn duration_secs(&self) -> f64 {
+        if self.sample_rate == 0 {
+            return 0.0;
+        }
+        self.frames as f64 / self.sample_rate as f64
+    }
+}
+
+/// Renders the tracks `job` asks for, each to
+/// `job.tracks().output_path(output_path, index)`. Summaries come back in
+/// track order.
 pub async fn decode_audio_file(
-    file_path: &str,
+    job: &AudioJob,
     output_path: &Path,
-    effects_config: Vec<EffectConfig>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    cancel: CancelToken,
+    progress: ProgressSender,
+    limits: JobLimits,
+    inputs: &InputSources,
+) -> Result<Vec<AudioSummary>, Box<dyn std::error::Error + Send + Sync>> {
+    // Fails before anything is fetched.
+    job.tracks()?;
     if let Some(parent) = output_path.parent() {
         std::fs::create_dir_all(parent)?;
     }
 
-    let bytes = load_audio_source(file_path).await?;
-    let cursor = Cursor::new(bytes);
+    let source = open_media_source(&job.input_path, limits.max_input_bytes, inputs)
+        .await
+        .map_err(|e| WorkerError::wrap(e, WorkerError::Fetch))?;
+    let output_path = output_path.to_path_buf();
+    let job = job.clone();
+
+    // Decoding and effects are CPU-bound, and remote sources block on the
+    // runtime while reading, so the whole decode runs on the blocking pool.
+    // That keeps the AMQP heartbeat and the other in-flight jobs responsive.
+    tokio::task::spawn_blocking(move || {
+        process_source(source, &output_path, &job, &cancel, &progress, &limits)
+    })
+    .await?
+}
 
-    let mss = MediaSourceStream::new(Box::new(cursor), Default::default());
+fn process_source(
+    source: Box<dyn MediaSource>,
+    output_path: &Path,
+    job: &AudioJob,
+    cancel: &CancelToken,
+    progress: &ProgressSender,
+    limits: &JobLimits,
+) -> Result<Vec<AudioSummary>, Box<dyn std::error::Error + Send + Sync>> {
+    let tracks = &job.tracks()?;
+    let (source, head) = HeadCapture::new(source);
+    let mss = MediaSourceStream::new(Box::new(source), Default::default());
 
-    let probed = get_probe().format(
-        &Default::default(),
-        mss,
-        &Default::default(),
-        &Default::default(),
-    )?;
+    let probed = get_probe()
+        .format(
+            &Default::default(),
+            mss,
+            &Default::default(),
+            &Default::default(),
+        )
+        .map_err(WorkerError::from)?;
     let mut format = probed.format;
+    let mut probed_metadata = probed.metadata;
 
-    let track = format
+    let mut renders = Vec::new();
+    for (index, track) in tracks.select(format.tracks())? {
+        let path = tracks.output_path(output_path, index);
+        renders.push(TrackRender::new(index, track, path, job, limits)?);
+    }
+
+    // Progress follows the first track; the others are interleaved with it.
+    let total_frames = format
         .tracks()
         .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .expect("not supported audio tracks");
-
-    let total_frames = track.codec_params.n_frames;
+        .find(|t| t.id == renders[0].track_id)
+        .and_then(|t| t.codec_params.n_frames);
     let pb = match total_frames {
         Some(total) => {
             let p = ProgressBar::new(total);
@@ -49,130 +133,657 @@ pub async fn decode_audio_file(
         None => ProgressBar::new_spinner(),
     };
 
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &Default::default())
-        .expect("Unsupported codec");
+    loop {
+        if let Some(reason) = cancel.reason() {
+            pb.abandon();
+            return Err(Box::new(JobCancelled(reason)));
+        }
 
-    let track_id = track.id;
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // This is how symphonia reports the end of the stream.
+            Err(Error::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            // E.g. the next stream of a chained Ogg file, which may use
+            // another codec.
+            Err(Error::ResetRequired) => {
+                for render in renders.iter_mut().filter(|render| !render.ended) {
+                    render.follow_reset(format.tracks(), job.all_tracks)?;
+                }
+                continue;
+            }
+            Err(err) => {
+                pb.abandon();
+                return Err(Box::new(WorkerError::from(err)));
+            }
+        };
+        let Some(position) = renders
+            .iter()
+            .position(|render| !render.ended && render.track_id == packet.track_id())
+        else {
+            continue;
+        };
 
-    // Use metadata for WavSpec
-    let spec = track.codec_params.clone();
-    let wav_spec = WavSpec {
-        channels: spec.channels.map(|c| c.count() as u16).unwrap_or(2),
-        sample_rate: spec.sample_rate.unwrap_or(44100),
-        bits_per_sample: 32,
-        sample_format: hound::SampleFormat::Float,
-    };
-    let mut writer = WavWriter::create(output_path, wav_spec)?;
+        let render = &mut renders[position];
+        if let Err(error) = render.decode(&packet, format.tracks(), limits) {
+            pb.abandon();
+            return Err(error);
+        }
+        if position == 0 {
+            pb.set_position(render.frames_read);
+            if let Some(total) = total_frames {
+                progress.report(render.frames_read, total);
+            }
+        }
+    }
 
-    let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
-    let channels = track
-        .codec_params
-        .channels
-        .map(|c| c.count() as usize)
-        .unwrap_or(2);
+    // Read last, so tags updated midway through the stream are included.
+    let mut container_metadata = format.metadata();
+    let input_tags = MediaTags::from_revisions(
+        probed_metadata
+            .get()
+            .as_mut()
+            .and_then(|metadata| metadata.skip_to_latest())
+            .into_iter()
+            .chain(container_metadata.skip_to_latest()),
+    );
 
-    let mut pipeline: Vec<Box<dyn AudioEffect>> = effects_config
+    // WAV inputs may carry BWF fields and markers in chunks symphonia skips,
+    // in front of the samples or after them.
+    let head = std::mem::take(&mut *head.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+    let input_chunks = read_wav_chunks(&head, &mut format.into_inner())?;
+    let input_cues = parse_cues(&input_chunks);
+    let mut chunks: Vec<Chunk> = Bext::edited(Bext::find(&input_chunks), &job.bwf)
+        .map(|bext| bext.processed(&job.effects).to_chunk())
         .into_iter()
-        .map(|c| c.into_effect(sample_rate as usize, channels))
         .collect();
+    chunks.extend(input_tags.edited(&job.metadata).wav_chunks());
 
-    loop {
-        match format.next_packet() {
-            Ok(packet) => {
-                if packet.track_id() != track_id {
-                    continue;
-                }
+    let summaries = renders
+        .into_iter()
+        .map(|render| render.finish(&input_tags, &chunks, &input_cues))
+        .collect::<Result<Vec<_>, _>>()?;
+    pb.finish_with_message("Done!");
+    println!("Processing complete: {:?}", output_path);
+    Ok(summaries)
+}
+
+/// Decoder, effect chain and output file of one track being rendered.
+struct TrackRender {
+    index: usize,
+    track_id: u32,
+    language: Option<String>,
+    decoder: Box<dyn Decoder>,
+    wav_spec: WavSpec,
+    /// Without a declared spec the output spec is only a guess, and the first
+    /// packet differing from it is not a change.
+    spec_declared: bool,
+    input_spec: (u32, usize),
+    spec_changes: u32,
+    converter: SpecConverter,
+    /// Allocated for the largest packet seen so far and reused after that.
+    sample_buf: Option<SampleBuffer<f32>>,
+    pipeline: Vec<Box<dyn AudioEffect>>,
+    markers: MarkerDetector,
+    writer: WavWriter<BufWriter<File>>,
+    // Written under a temporary name and renamed once complete, so a crash
+    // never leaves a truncated file at the destination. Declared after
+    // `writer`, so on an early return the writer is dropped first and then
+    // the partial file is removed.
+    partial: PartialFile,
+    frames_read: u64,
+    frames_written: u64,
+    corrupt_packets: u32,
+    /// Set when the track went away in a reset; the output is complete.
+    ended: bool,
+}
+
+impl TrackRender {
+    fn new(
+        index: usize,
+        track: &Track,
+        output_path: PathBuf,
+        job: &AudioJob,
+        limits: &JobLimits,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let effects_config = &job.effects;
+        let params = &track.codec_params;
+        let sample_rate = params.sample_rate.unwrap_or(44100);
+        let channels = params.channels.map(|c| c.count()).unwrap_or(2);
 
-                match decoder.decode(&packet) {
-                    Ok(decoded) => {
-                        pb.inc(decoded.capacity() as u64);
-                        let mut sample_buf = symphonia::core::audio::SampleBuffer::<f32>::new(
-                            decoded.capacity() as u64,
-                            *decoded.spec(),
-                        );
-
-                        sample_buf.copy_interleaved_ref(decoded);
-                        let samples = sample_buf.samples_mut();
-
-                        // Apply effect
-                        for effect in pipeline.iter_mut() {
-                            effect.process(samples);
-                        }
-
-                        for &sample in samples.iter() {
-                            writer.write_sample(sample)?;
-                        }
-                    }
-                    Err(Error::IoError(_)) => continue,
-                    Err(err) => return Err(Box::new(err)),
+        // Reject what we can before allocating anything; streams without a
+        // frame count are checked again while decoding.
+        limits.check_format(sample_rate, channels)?;
+        limits.check_effects(effects_config, sample_rate, channels)?;
+        if let Some(total) = params.n_frames {
+            limits.check_duration(total, sample_rate)?;
+        }
+
+        let wav_spec = WavSpec {
+            channels: channels as u16,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let partial = PartialFile::new(&output_path);
+        let writer = WavWriter::create(partial.path(), wav_spec)?;
+
+        Ok(Self {
+            index,
+            track_id: track.id,
+            language: track.language.clone(),
+            decoder: make_decoder(track)?,
+            wav_spec,
+            spec_declared: params.sample_rate.is_some() && params.channels.is_some(),
+            input_spec: (sample_rate, channels),
+            spec_changes: 0,
+            converter: SpecConverter::new(channels, sample_rate),
+            sample_buf: None,
+            pipeline: effects_config
+                .iter()
+                .cloned()
+                .map(|c| c.into_effect(sample_rate as usize, channels))
+                .collect(),
+            markers: MarkerDetector::new(&job.markers, sample_rate, channels),
+            writer,
+            partial,
+            frames_read: 0,
+            frames_written: 0,
+            corrupt_packets: 0,
+            ended: false,
+        })
+    }
+
+    /// Rebuilds the decoder from the track's current parameters. With
+    /// `fallback`, a track the container no longer has is replaced by the
+    /// first audio track.
+    fn reset(&mut self, tracks: &[Track], fallback: bool) -> Result<(), WorkerError> {
+        let track = tracks
+            .iter()
+            .find(|t| t.id == self.track_id)
+            .or_else(|| {
+                tracks
+                    .iter()
+                    .find(|t| fallback && t.codec_params.codec != CODEC_TYPE_NULL)
+            })
+            .ok_or_else(|| {
+                WorkerError::Decode(format!("track {} disappeared", self.track_id).into())
+            })?;
+        self.track_id = track.id;
+        self.decoder = make_decoder(track)?;
+        Ok(())
+    }
+
+    /// Follows the container into its next stream. A single selected track
+    /// carries on with the first audio track if its own is gone. With all
+    /// tracks, each render keeps to its own and ends with it; falling back
+    /// would render the same track into several outputs.
+    fn follow_reset(&mut self, tracks: &[Track], all_tracks: bool) -> Result<(), WorkerError> {
+        if all_tracks && !tracks.iter().any(|t| t.id == self.track_id) {
+            println!("Track {} ended with its stream", self.track_id);
+            self.ended = true;
+            return Ok(());
+        }
+        self.reset(tracks, !all_tracks)
+    }
+
+    fn decode(
+        &mut self,
+        packet: &Packet,
+        tracks: &[Track],
+        limits: &JobLimits,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let decoded = match self.decoder.decode(packet) {
+            Ok(decoded) => decoded,
+            // A damaged packet costs a few milliseconds of audio; a stream
+            // full of them is not worth delivering.
+            Err(err @ (Error::DecodeError(_) | Error::IoError(_))) => {
+                self.corrupt_packets += 1;
+                if self.corrupt_packets > limits.max_corrupt_packets {
+                    return Err(Box::new(WorkerError::Decode(
+                        format!(
+                            "more than {} corrupt packets, last: {err}",
+                            limits.max_corrupt_packets
+                        )
+                        .into(),
+                    )));
                 }
+                eprintln!(
+                    "Skipping corrupt packet of track {}: {}",
+                    self.track_id, err
+                );
+                return Ok(());
             }
-            Err(Error::ResetRequired) => break,
-            Err(_) => return Ok(()),
+            Err(Error::ResetRequired) => {
+                self.reset(tracks, false)?;
+                return Ok(());
+            }
+            Err(err) => return Err(Box::new(WorkerError::from(err))),
         };
-    }
 
-    writer.finalize()?;
-    pb.finish_with_message("Done!");
-    println!("Processing complete: {:?}", output_path);
-    Ok(())
-}
+        let spec = *decoded.spec();
+        let packet_spec = (spec.rate, spec.channels.count());
+        if packet_spec != self.input_spec {
+            println!(
+                "Track {} changed from {} Hz/{} channels to {} Hz/{} channels",
+                self.track_id, self.input_spec.0, self.input_spec.1, packet_spec.0, packet_spec.1
+            );
+            limits.check_format(packet_spec.0, packet_spec.1)?;
+            if self.frames_read > 0 || self.spec_declared {
+                self.spec_changes += 1;
+            }
+            self.input_spec = packet_spec;
+        }
+        self.frames_read += decoded.frames() as u64;
+
+        let needed = decoded.capacity() * packet_spec.1;
+        let buf = match &mut self.sample_buf {
+            Some(buf) if buf.capacity() >= needed => buf,
+            slot => slot.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buf.copy_interleaved_ref(decoded);
+        let samples = self
+            .converter
+            .convert(buf.samples_mut(), packet_spec.1, packet_spec.0);
 
-async fn load_audio_source(file_path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    if is_remote_source(file_path) {
-        let response = reqwest::get(file_path).await?;
-        if !response.status().is_success() {
-            return Err(format!("failed to fetch audio: {}", response.status()).into());
+        let channels = usize::from(self.wav_spec.channels.max(1));
+        self.frames_written += (samples.len() / channels) as u64;
+        limits.check_duration(self.frames_written, self.wav_spec.sample_rate)?;
+
+        for effect in self.pipeline.iter_mut() {
+            effect.process(samples);
+        }
+        self.markers.process(samples);
+        for &sample in samples.iter() {
+            self.writer.write_sample(sample)?;
         }
+        Ok(())
+    }
 
-        return Ok(response.bytes().await?.to_vec());
+    /// Finalizes the output with `chunks` and the track's markers and moves
+    /// it into place.
+    fn finish(
+        self,
+        input_tags: &MediaTags,
+        chunks: &[Chunk],
+        input_cues: &[CuePoint],
+    ) -> Result<AudioSummary, Box<dyn std::error::Error + Send + Sync>> {
+        let samples_written = self.writer.len();
+        self.writer.finalize()?;
+        let markers = self.markers.finish(input_cues, self.frames_written);
+        let mut chunks = chunks.to_vec();
+        chunks.extend(cue_chunks(&markers));
+        append_chunks(self.partial.path(), &chunks)?;
+        let sha256 = verify_output(self.partial.path(), self.wav_spec, samples_written)?;
+        self.partial.commit()?;
+        Ok(AudioSummary {
+            track_index: self.index,
+            track_id: self.track_id,
+            language: self.language,
+            sample_rate: self.wav_spec.sample_rate,
+            channels: self.wav_spec.channels,
+            frames: self.frames_written,
+            sha256,
+            spec_changes: self.spec_changes,
+            tags: input_tags.tags.clone(),
+            artwork: input_tags.artwork_info(),
+            markers,
+        })
     }
+}
 
-    Ok(tokio::fs::read(file_path).await?)
+fn make_decoder(track: &Track) -> Result<Box<dyn Decoder>, WorkerError> {
+    Ok(symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?)
 }
 
-fn is_remote_source(file_path: &str) -> bool {
-    file_path.starts_with("http://") || file_path.starts_with("https://")
+/// Re-reads a finalized output, checks its header describes exactly what was
+/// written and returns the SHA-256 of the file. The header is only complete
+/// after `finalize`, so the digest cannot be taken while writing.
+fn verify_output(
+    path: &Path,
+    expected_spec: WavSpec,
+    samples_written: u32,
+) -> Result<[u8; 32], Box<dyn std::error::Error + Send + Sync>> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    let reader = WavReader::open(path)?;
+    if reader.spec() != expected_spec || reader.len() != samples_written {
+        return Err(format!(
+            "output {} is inconsistent: header has {} samples, {} were written",
+            path.display(),
+            reader.len(),
+            samples_written
+        )
+        .into());
+    }
+    Ok(hasher.finalize().into())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{is_remote_source, load_audio_source};
-    use std::fs;
-    use std::time::{SystemTime, UNIX_EPOCH};
+    use super::{TrackRender, process_source};
+    use crate::lib::broadcast::{Bext, BroadcastFields};
+    use crate::lib::cancellation::CancelToken;
+    use crate::lib::effects::AudioJob;
+    use crate::lib::limits::JobLimits;
+    use crate::lib::markers::{CuePoint, Marker, MarkerOptions, cue_chunks, parse_cues};
+    use crate::lib::riff::{Chunk, append_chunks, read_chunks};
+    use crate::lib::status::ProgressSender;
+    use crate::lib::tags::MetadataEdit;
+    use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+    use std::fs::{self, File};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::default::get_probe;
 
-    fn unique_temp_file() -> std::path::PathBuf {
+    fn unique_temp_dir(name: &str) -> std::path::PathBuf {
         let nanos = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("time should be monotonic")
             .as_nanos();
 
-        std::env::temp_dir().join(format!("audio-input-{nanos}.bin"))
+        std::env::temp_dir().join(format!("worker-decode-{name}-{nanos}"))
+    }
+
+    fn limits() -> JobLimits {
+        JobLimits {
+            max_input_bytes: 1024 * 1024,
+            max_duration: Duration::from_secs(60),
+            max_channels: 2,
+            max_sample_rate: 48_000,
+            max_effect_buffer_bytes: 1024,
+            max_corrupt_packets: 0,
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    fn job() -> AudioJob {
+        AudioJob {
+            job_id: "job-1".into(),
+            input_path: "input.wav".into(),
+            output_path: "output.wav".into(),
+            effects: Vec::new(),
+            track: None,
+            all_tracks: false,
+            metadata: MetadataEdit::default(),
+            bwf: BroadcastFields::default(),
+            markers: MarkerOptions::default(),
+        }
+    }
+
+    /// One second of 16-bit stereo at 8 kHz.
+    fn write_input(path: &std::path::Path) {
+        write_frames(path, 8_000);
+    }
+
+    /// `frames` of 16-bit stereo at 8 kHz.
+    fn write_frames(path: &std::path::Path, frames: u32) {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(path, spec).expect("input should be created");
+        for n in 0..frames * 2 {
+            writer
+                .write_sample((n % 100) as i16)
+                .expect("sample should be written");
+        }
+        writer.finalize().expect("input should be finalized");
+    }
+
+    /// hound cannot write other chunks, so they are put in front of the
+    /// samples by hand.
+    fn insert_chunks(path: &std::path::Path, extra: Vec<Chunk>) {
+        let mut chunks = read_chunks(path).expect("input chunks should be read");
+        let data = chunks.pop().expect("input should have a data chunk");
+        chunks.extend(extra);
+        chunks.push(data);
+        let wave = Chunk::list(b"WAVE", &chunks).data;
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(wave.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&wave);
+        fs::write(path, bytes).expect("input should be rewritten");
     }
 
     #[test]
-    fn detects_remote_sources() {
-        assert!(is_remote_source("https://example.com/audio.mp3"));
-        assert!(is_remote_source("http://example.com/audio.mp3"));
-        assert!(!is_remote_source("/app/data/inputs/test.wav"));
+    fn decodes_until_the_end_of_a_truncated_stream() {
+        let dir = unique_temp_dir("truncated");
+        fs::create_dir_all(&dir).expect("dir should be created");
+        let input = dir.join("input.wav");
+        write_input(&input);
+        // The header still announces a full second.
+        let bytes = fs::read(&input).expect("input should be read");
+        fs::write(&input, &bytes[..bytes.len() / 2]).expect("input should be truncated");
+
+        let output = dir.join("output.wav");
+        let summaries = process_source(
+            Box::new(File::open(&input).expect("input should open")),
+            &output,
+            &job(),
+            &CancelToken::new(),
+            &ProgressSender::detached(),
+            &limits(),
+        )
+        .expect("a truncated stream should decode up to where it ends");
+        let summary = &summaries[0];
+
+        let reader = WavReader::open(&output).expect("output should be a finalized WAV");
+        assert!(summary.frames > 0 && summary.frames < 8_000);
+        assert_eq!(u64::from(reader.duration()), summary.frames);
+
+        let _ = fs::remove_dir_all(dir);
     }
 
-    #[tokio::test]
-    async fn loads_local_audio_source() {
-        let temp_file = unique_temp_file();
-        fs::write(&temp_file, b"local-audio").expect("temp file should be written");
+    #[test]
+    fn leaves_nothing_behind_when_decoding_fails() {
+        let dir = unique_temp_dir("garbage");
+        fs::create_dir_all(&dir).expect("dir should be created");
+        let input = dir.join("input.wav");
+        fs::write(&input, vec![0x5a; 4096]).expect("input should be written");
+
+        let output = dir.join("output.wav");
+        let result = process_source(
+            Box::new(File::open(&input).expect("input should open")),
+            &output,
+            &job(),
+            &CancelToken::new(),
+            &ProgressSender::detached(),
+            &limits(),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_dir(&dir).expect("dir should list").count(), 1);
 
-        let bytes = load_audio_source(
-            temp_file
-                .to_str()
-                .expect("temp path should be valid UTF-8"),
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn carries_edited_tags_over_to_the_output() {
+        let dir = unique_temp_dir("tags");
+        fs::create_dir_all(&dir).expect("dir should be created");
+        let input = dir.join("input.wav");
+        write_input(&input);
+        let info = Chunk::list(
+            b"INFO",
+            &[
+                Chunk::new(b"INAM", b"Song\0".to_vec()),
+                Chunk::new(b"IART", b"Band\0".to_vec()),
+            ],
+        );
+        insert_chunks(&input, vec![info]);
+
+        let mut job = job();
+        job.metadata.set.insert("artist".into(), "Someone".into());
+        let output = dir.join("output.wav");
+        let summaries = process_source(
+            Box::new(File::open(&input).expect("input should open")),
+            &output,
+            &job,
+            &CancelToken::new(),
+            &ProgressSender::detached(),
+            &limits(),
         )
-        .await
-        .expect("local source should load");
+        .expect("tagged input should decode");
+
+        assert_eq!(summaries[0].tags["track_title"], "Song");
+        assert_eq!(summaries[0].tags["artist"], "Band");
+        let chunks = read_chunks(&output).expect("output chunks should be read");
+        let info = chunks
+            .iter()
+            .find(|chunk| chunk.data.starts_with(b"INFO"))
+            .expect("output should have an INFO list");
+        assert!(info.data.windows(8).any(|w| w == b"Someone\0"));
+        assert!(chunks.iter().any(|chunk| &chunk.id == b"id3 "));
+        let reader = WavReader::open(&output).expect("output should be a finalized WAV");
+        assert_eq!(u64::from(reader.duration()), summaries[0].frames);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn keeps_broadcast_fields_and_markers() {
+        let dir = unique_temp_dir("bwf");
+        fs::create_dir_all(&dir).expect("dir should be created");
+        let input = dir.join("input.wav");
+        write_input(&input);
+        let fields = BroadcastFields {
+            description: Some("Take 1".into()),
+            originator: Some("Desk 4".into()),
+            time_reference: Some(288_000_000),
+            ..BroadcastFields::default()
+        };
+        let bext = Bext::edited(None, &fields).expect("bext should be created");
+        let mut extra = vec![bext.to_chunk()];
+        extra.extend(cue_chunks(&[CuePoint {
+            frame: 800,
+            length: None,
+            label: "Slate".into(),
+        }]));
+        insert_chunks(&input, extra);
+
+        let mut job = job();
+        job.bwf.description = Some("Take 2".into());
+        job.markers.points.push(Marker {
+            position_seconds: 0.5,
+            length_seconds: Some(0.25),
+            label: "Answer".into(),
+        });
+        let output = dir.join("output.wav");
+        let summaries = process_source(
+            Box::new(File::open(&input).expect("input should open")),
+            &output,
+            &job,
+            &CancelToken::new(),
+            &ProgressSender::detached(),
+            &limits(),
+        )
+        .expect("input should decode");
+
+        let chunks = read_chunks(&output).expect("output chunks should be read");
+        let written = Bext::find(&chunks).expect("output should have a bext chunk");
+        assert_eq!(written.description, "Take 2");
+        assert_eq!(written.originator, "Desk 4");
+        assert_eq!(written.time_reference, 288_000_000);
+        let cues = parse_cues(&chunks);
+        assert_eq!(cues, summaries[0].markers);
+        assert_eq!(
+            cues.iter()
+                .map(|cue| (cue.frame, cue.length, cue.label.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(800, None, "Slate"), (4_000, Some(2_000), "Answer")]
+        );
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reads_chunks_after_the_samples_of_long_inputs() {
+        let dir = unique_temp_dir("trailing");
+        fs::create_dir_all(&dir).expect("dir should be created");
+        let input = dir.join("input.wav");
+        // Ten seconds, so the samples run past what is captured up front.
+        write_frames(&input, 80_000);
+        let fields = BroadcastFields {
+            originator: Some("Desk 4".into()),
+            ..BroadcastFields::default()
+        };
+        let bext = Bext::edited(None, &fields).expect("bext should be created");
+        let mut extra = vec![bext.to_chunk()];
+        extra.extend(cue_chunks(&[CuePoint {
+            frame: 800,
+            length: None,
+            label: "Slate".into(),
+        }]));
+        append_chunks(&input, &extra).expect("chunks should be appended");
+        assert!(fs::metadata(&input).expect("input should exist").len() > 256 * 1024);
+
+        let output = dir.join("output.wav");
+        let summaries = process_source(
+            Box::new(File::open(&input).expect("input should open")),
+            &output,
+            &job(),
+            &CancelToken::new(),
+            &ProgressSender::detached(),
+            &limits(),
+        )
+        .expect("input should decode");
+
+        let chunks = read_chunks(&output).expect("output chunks should be read");
+        let written = Bext::find(&chunks).expect("output should have a bext chunk");
+        assert_eq!(written.originator, "Desk 4");
+        assert_eq!(
+            summaries[0]
+                .markers
+                .iter()
+                .map(|cue| (cue.frame, cue.label.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(800, "Slate")]
+        );
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn ends_renders_whose_track_is_gone_with_all_tracks() {
+        let dir = unique_temp_dir("reset");
+        fs::create_dir_all(&dir).expect("dir should be created");
+        let input = dir.join("input.wav");
+        write_input(&input);
+        let mss = MediaSourceStream::new(
+            Box::new(File::open(&input).expect("input should open")),
+            Default::default(),
+        );
+        let probed = get_probe()
+            .format(
+                &Default::default(),
+                mss,
+                &Default::default(),
+                &Default::default(),
+            )
+            .expect("input should probe");
+        let track = probed.format.tracks()[0].clone();
+        // The next stream of a chained file, with a track of its own.
+        let mut next = track.clone();
+        next.id = track.id + 1;
+
+        let mut all = TrackRender::new(0, &track, dir.join("all.wav"), &job(), &limits())
+            .expect("render should start");
+        all.follow_reset(std::slice::from_ref(&next), true)
+            .expect("reset should be followed");
+        assert!(all.ended);
+        assert_eq!(all.track_id, track.id);
 
-        assert_eq!(bytes, b"local-audio");
+        let mut single = TrackRender::new(0, &track, dir.join("single.wav"), &job(), &limits())
+            .expect("render should start");
+        single
+            .follow_reset(std::slice::from_ref(&next), false)
+            .expect("reset should be followed");
+        assert!(!single.ended);
+        assert_eq!(single.track_id, next.id);
 
-        let _ = fs::remove_file(temp_file);
+        drop((all, single));
+        let _ = fs::remove_dir_all(dir);
     }
 }
@@ -0,0 +1,284 @@
+use serde::{Deserialize, Serialize};
+
+use crate::lib::effects::EffectConfig;
+use crate::lib::error::WorkerError;
+use crate::lib::riff::Chunk;
+
+/// Size of a `bext` chunk without its coding history (EBU Tech 3285).
+const BEXT_FIXED_BYTES: usize = 602;
+/// Where the fields we edit end. Version, UMID, loudness and the reserved
+/// bytes after them are carried over as they were read, except for the
+/// loudness of processed audio.
+const BEXT_EDITED_BYTES: usize = 346;
+/// BWF version written when the input had no `bext` chunk.
+const BEXT_VERSION: u16 = 1;
+/// The BWF v2 loudness values within the bytes after the edited fields,
+/// following the version and the UMID.
+const BEXT_LOUDNESS: std::ops::Range<usize> = 66..76;
+
+/// Broadcast WAV fields a job sets on its output. Unset fields keep the
+/// input's values.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BroadcastFields {
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub originator: Option<String>,
+    #[serde(default)]
+    pub originator_reference: Option<String>,
+    /// `yyyy-mm-dd`.
+    #[serde(default)]
+    pub origination_date: Option<String>,
+    /// `hh:mm:ss`.
+    #[serde(default)]
+    pub origination_time: Option<String>,
+    /// Position of the first sample, in samples since midnight.
+    #[serde(default)]
+    pub time_reference: Option<u64>,
+}
+
+impl BroadcastFields {
+    pub fn validate(&self) -> Result<(), WorkerError> {
+        let fields = [
+            ("description", &self.description, 256),
+            ("originator", &self.originator, 32),
+            ("originator_reference", &self.originator_reference, 32),
+            ("origination_date", &self.origination_date, 10),
+            ("origination_time", &self.origination_time, 8),
+        ];
+        for (name, value, max) in fields {
+            let Some(value) = value else { continue };
+            if !value.is_ascii() || value.len() > max {
+                return Err(WorkerError::InvalidJob(format!(
+                    "bwf {name} must be ASCII of at most {max} characters"
+                )));
+            }
+        }
+
+        let digits = |value: &str, separators: &[usize]| {
+            value.bytes().enumerate().all(|(i, b)| {
+                if separators.contains(&i) {
+                    !b.is_ascii_alphanumeric()
+                } else {
+                    b.is_ascii_digit()
+                }
+            })
+        };
+        if let Some(date) = &self.origination_date
+            && (date.len() != 10 || !digits(date, &[4, 7]))
+        {
+            return Err(WorkerError::InvalidJob(format!(
+                "bwf origination_date must look like yyyy-mm-dd, got {date:?}"
+            )));
+        }
+        if let Some(time) = &self.origination_time
+            && (time.len() != 8 || !digits(time, &[2, 5]))
+        {
+            return Err(WorkerError::InvalidJob(format!(
+                "bwf origination_time must look like hh:mm:ss, got {time:?}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Contents of a `bext` chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bext {
+    pub description: String,
+    pub originator: String,
+    pub originator_reference: String,
+    pub origination_date: String,
+    pub origination_time: String,
+    pub time_reference: u64,
+    /// Version, UMID, loudness and reserved bytes.
+    extension: Vec<u8>,
+    pub coding_history: String,
+}
+
+impl Default for Bext {
+    fn default() -> Self {
+        let mut extension = vec![0; BEXT_FIXED_BYTES - BEXT_EDITED_BYTES];
+        extension[..2].copy_from_slice(&BEXT_VERSION.to_le_bytes());
+        Self {
+            description: String::new(),
+            originator: String::new(),
+            originator_reference: String::new(),
+            origination_date: String::new(),
+            origination_time: String::new(),
+            time_reference: 0,
+            extension,
+            coding_history: String::new(),
+        }
+    }
+}
+
+impl Bext {
+    /// The `bext` chunk among `chunks`, if there is a well-formed one.
+    pub fn find(chunks: &[Chunk]) -> Option<Self> {
+        let data = &chunks.iter().find(|chunk| &chunk.id == b"bext")?.data;
+        if data.len() < BEXT_FIXED_BYTES {
+            return None;
+        }
+
+        let text = |range: std::ops::Range<usize>| {
+            let field = &data[range];
+            let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+        let low = u32::from_le_bytes([data[338], data[339], data[340], data[341]]);
+        let high = u32::from_le_bytes([data[342], data[343], data[344], data[345]]);
+        Some(Self {
+            description: text(0..256),
+            originator: text(256..288),
+            originator_reference: text(288..320),
+            origination_date: text(320..330),
+            origination_time: text(330..338),
+            time_reference: u64::from(high) << 32 | u64::from(low),
+            extension: data[BEXT_EDITED_BYTES..BEXT_FIXED_BYTES].to_vec(),
+            coding_history: text(BEXT_FIXED_BYTES..data.len()),
+        })
+    }
+
+    /// The output's `bext`: the input's with `fields` applied. None when the
+    /// input had none and the job sets none.
+    pub fn edited(input: Option<Self>, fields: &BroadcastFields) -> Option<Self> {
+        if input.is_none() && *fields == BroadcastFields::default() {
+            return None;
+        }
+
+        let mut bext = input.unwrap_or_default();
+        let strings = [
+            (&mut bext.description, &fields.description),
+            (&mut bext.originator, &fields.originator),
+            (&mut bext.originator_reference, &fields.originator_reference),
+            (&mut bext.origination_date, &fields.origination_date),
+            (&mut bext.origination_time, &fields.origination_time),
+        ];
+        for (field, value) in strings {
+            if let Some(value) = value {
+                field.clone_from(value);
+            }
+        }
+        if let Some(time_reference) = fields.time_reference {
+            bext.time_reference = time_reference;
+        }
+        Some(bext)
+    }
+
+    /// Records that `effects` changed the audio: the input's loudness values
+    /// no longer describe it, so they are cleared, and the coding history
+    /// gains a line naming the effects.
+    pub fn processed(mut self, effects: &[EffectConfig]) -> Self {
+        if effects.is_empty() {
+            return self;
+        }
+
+        self.extension[BEXT_LOUDNESS].fill(0);
+        if !self.coding_history.is_empty() && !self.coding_history.ends_with("\r\n") {
+            self.coding_history.push_str("\r\n");
+        }
+        let names: Vec<&str> = effects.iter().map(EffectConfig::name).collect();
+        self.coding_history
+            .push_str(&format!("A=PCM,W=32,T=effects: {}\r\n", names.join(", ")));
+        self
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let mut data = Vec::with_capacity(BEXT_FIXED_BYTES + self.coding_history.len());
+        let fields = [
+            (&self.description, 256),
+            (&self.originator, 32),
+            (&self.originator_reference, 32),
+            (&self.origination_date, 10),
+            (&self.origination_time, 8),
+        ];
+        for (value, width) in fields {
+            let bytes = &value.as_bytes()[..value.len().min(width)];
+            data.extend_from_slice(bytes);
+            data.resize(data.len() + width - bytes.len(), 0);
+        }
+        data.extend_from_slice(&(self.time_reference as u32).to_le_bytes());
+        data.extend_from_slice(&((self.time_reference >> 32) as u32).to_le_bytes());
+        data.extend_from_slice(&self.extension);
+        data.extend_from_slice(self.coding_history.as_bytes());
+        Chunk::new(b"bext", data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BEXT_FIXED_BYTES, Bext, BroadcastFields};
+    use crate::lib::effects::EffectConfig;
+    use crate::lib::riff::Chunk;
+
+    #[test]
+    fn edits_input_fields_and_keeps_the_rest() {
+        let input = Bext {
+            description: "Morning news".into(),
+            originator: "Desk 4".into(),
+            time_reference: 5_000_000_000,
+            coding_history: "A=PCM,F=48000,W=24,M=stereo\r\n".into(),
+            ..Bext::default()
+        };
+        let chunk = input.to_chunk();
+        assert_eq!(chunk.data.len(), BEXT_FIXED_BYTES + 29);
+
+        let fields = BroadcastFields {
+            description: Some("Evening news".into()),
+            ..BroadcastFields::default()
+        };
+        let edited = Bext::edited(Bext::find(&[chunk]), &fields).expect("bext should be kept");
+        assert_eq!(edited.description, "Evening news");
+        assert_eq!(edited.originator, "Desk 4");
+        assert_eq!(edited.time_reference, 5_000_000_000);
+        assert_eq!(edited.coding_history, input.coding_history);
+        assert_eq!(edited.to_chunk().data[346..348], [1, 0]);
+
+        assert_eq!(Bext::edited(None, &BroadcastFields::default()), None);
+    }
+
+    #[test]
+    fn clears_loudness_of_processed_audio() {
+        let mut data = Bext::default().to_chunk().data;
+        // Integrated loudness of -23 LUFS, in hundredths.
+        data[412..414].copy_from_slice(&(-2300i16).to_le_bytes());
+        data.extend_from_slice(b"A=PCM,F=48000,W=24,M=stereo");
+        let input = Bext::find(&[Chunk::new(b"bext", data)]).expect("bext should be read");
+
+        let unchanged = input.clone().processed(&[]);
+        assert_eq!(unchanged, input);
+
+        let processed = input.processed(&[
+            EffectConfig::Gain { amount: 0.5 },
+            EffectConfig::Lowpass { cutoff: 1_000.0 },
+        ]);
+        let chunk = processed.to_chunk();
+        assert_eq!(chunk.data[412..422], [0; 10]);
+        assert_eq!(
+            processed.coding_history,
+            "A=PCM,F=48000,W=24,M=stereo\r\nA=PCM,W=32,T=effects: gain, lowpass\r\n"
+        );
+    }
+
+    #[test]
+    fn rejects_fields_that_do_not_fit() {
+        let long = BroadcastFields {
+            originator: Some("x".repeat(33)),
+            ..BroadcastFields::default()
+        };
+        let date = BroadcastFields {
+            origination_date: Some("19.10.2026".into()),
+            ..BroadcastFields::default()
+        };
+        let valid = BroadcastFields {
+            origination_date: Some("2026-10-19".into()),
+            origination_time: Some("08:30:00".into()),
+            ..BroadcastFields::default()
+        };
+
+        assert_eq!(long.validate().map_err(|e| e.code()), Err("invalid_job"));
+        assert_eq!(date.validate().map_err(|e| e.code()), Err("invalid_job"));
+        assert!(valid.validate().is_ok());
+    }
+}
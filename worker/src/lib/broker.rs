@@ -0,0 +1,296 @@
+use lapin::options::{
+    BasicConsumeOptions, BasicPublishOptions, BasicQosOptions, ConfirmSelectOptions,
+    ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+};
+use lapin::publisher_confirm::Confirmation;
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, Consumer, ExchangeKind};
+use std::time::Duration;
+
+use crate::lib::config::{QueueConfig, WorkerConfig};
+use crate::lib::retry::{RetryPolicy, retry_queue_arguments};
+
+/// The queue the API publishes to; consumed when `WORKER_QUEUES` is not set.
+pub const JOB_QUEUE: &str = "audio_jobs";
+pub const STATUS_QUEUE: &str = "audio_status";
+/// Fanout exchange for control messages (e.g. cancellations), so every
+/// replica hears about every job no matter which one is running it.
+pub const CONTROL_EXCHANGE: &str = "audio_control";
+
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Failed jobs from `job_queue` that will not be retried end up here.
+pub fn dead_letter_queue(job_queue: &str) -> String {
+    format!("{job_queue}.dead")
+}
+
+/// A live broker connection with its topology declared and consumers attached.
+pub struct BrokerSession {
+    pub connection: Connection,
+    pub queues: Vec<QueueConsumer>,
+    pub control: Consumer,
+}
+
+/// A consumer on one job queue. Each queue gets its own channel so its
+/// prefetch limit applies to that queue alone.
+pub struct QueueConsumer {
+    pub queue: QueueConfig,
+    pub channel: Channel,
+    pub consumer: Consumer,
+}
+
+impl BrokerSession {
+    pub async fn open(config: &WorkerConfig) -> lapin::Result<Self> {
+        let connection =
+            Connection::connect(&config.rabbitmq_url, ConnectionProperties::default()).await?;
+
+        let topology = connection.create_channel().await?;
+        declare_topology(&topology, &config.queues, &config.retry_policy).await?;
+        topology.close(200, "topology declared").await?;
+
+        let mut queues = Vec::with_capacity(config.queues.len());
+        for queue in &config.queues {
+            queues.push(consume_queue(&connection, queue, &config.consumer_tag).await?);
+        }
+
+        let control = subscribe_to_control(&connection, &config.consumer_tag).await?;
+
+        Ok(Self {
+            connection,
+            queues,
+            control,
+        })
+    }
+
+    pub async fn close(&self) {
+        for queue in &self.queues {
+            if let Err(e) = queue.channel.close(200, "worker shutdown").await {
+                eprintln!("Failed to close channel for {}: {}", queue.queue.name, e);
+            }
+        }
+        if let Err(e) = self.connection.close(200, "worker shutdown").await {
+            eprintln!("Failed to close connection: {}", e);
+        }
+    }
+}
+
+async fn consume_queue(
+    connection: &Connection,
+    queue: &QueueConfig,
+    consumer_tag: &str,
+) -> lapin::Result<QueueConsumer> {
+    let channel = connection.create_channel().await?;
+
+    // Retries and dead-letters are acked only after the broker confirms
+    // the republished copy, so a job is never lost in between.
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+
+    // Never hold more unacked deliveries than we are allowed to run at once.
+    channel
+        .basic_qos(queue.prefetch_count(), BasicQosOptions::default())
+        .await?;
+
+    let consumer = channel
+        .basic_consume(
+            &queue.name,
+            consumer_tag,
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    Ok(QueueConsumer {
+        queue: queue.clone(),
+        channel,
+        consumer,
+    })
+}
+
+/// Binds a private, server-named queue to the control exchange. It lives on
+/// its own channel so the job prefetch limit never holds control messages back.
+async fn subscribe_to_control(
+    connection: &Connection,
+    consumer_tag: &str,
+) -> lapin::Result<Consumer> {
+    let channel = connection.create_channel().await?;
+    let queue = channel
+        .queue_declare(
+            "",
+            QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..QueueDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    channel
+        .queue_bind(
+            queue.name().as_str(),
+            CONTROL_EXCHANGE,
+            "",
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+
+    channel
+        .basic_consume(
+            queue.name().as_str(),
+            &format!("{consumer_tag}_control"),
+            BasicConsumeOptions {
+                no_ack: true,
+                ..BasicConsumeOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+/// Declares every queue the worker touches, so it does not depend on the API
+/// having started first. Arguments of the shared queues must match
+/// `RabbitMQService` in the API or the broker rejects the second declaration.
+pub async fn declare_topology(
+    channel: &Channel,
+    job_queues: &[QueueConfig],
+    retry_policy: &RetryPolicy,
+) -> lapin::Result<()> {
+    declare_durable_queue(channel, STATUS_QUEUE, FieldTable::default()).await?;
+
+    for job_queue in job_queues {
+        declare_durable_queue(channel, &job_queue.name, job_queue_arguments(job_queue)).await?;
+        declare_durable_queue(
+            channel,
+            &dead_letter_queue(&job_queue.name),
+            FieldTable::default(),
+        )
+        .await?;
+
+        for (queue, delay) in retry_policy.retry_queues(&job_queue.name) {
+            declare_durable_queue(
+                channel,
+                &queue,
+                retry_queue_arguments(&job_queue.name, delay),
+            )
+            .await?;
+        }
+    }
+
+    channel
+        .exchange_declare(
+            CONTROL_EXCHANGE,
+            ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..ExchangeDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    Ok(())
+}
+
+fn job_queue_arguments(queue: &QueueConfig) -> FieldTable {
+    let mut arguments = FieldTable::default();
+    if let Some(max_priority) = queue.max_priority {
+        arguments.insert(
+            "x-max-priority".into(),
+            AMQPValue::ShortShortUInt(max_priority),
+        );
+    }
+    arguments
+}
+
+async fn declare_durable_queue(
+    channel: &Channel,
+    queue: &str,
+    arguments: FieldTable,
+) -> lapin::Result<()> {
+    channel
+        .queue_declare(
+            queue,
+            QueueDeclareOptions {
+                durable: true,
+                ..QueueDeclareOptions::default()
+            },
+            arguments,
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Publishes and waits for the broker's confirmation.
+pub async fn publish(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    payload: &[u8],
+    properties: BasicProperties,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let confirmation = channel
+        .basic_publish(
+            exchange,
+            routing_key,
+            BasicPublishOptions::default(),
+            payload,
+            properties,
+        )
+        .await?
+        .await?;
+
+    if let Confirmation::Nack(_) = confirmation {
+        return Err(format!("broker rejected message published to {routing_key}").into());
+    }
+
+    Ok(())
+}
+
+/// Exponential reconnect delay, capped at `MAX_RECONNECT_DELAY`.
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self {
+            next: INITIAL_RECONNECT_DELAY,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_RECONNECT_DELAY);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = INITIAL_RECONNECT_DELAY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, INITIAL_RECONNECT_DELAY, MAX_RECONNECT_DELAY};
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::new();
+
+        assert_eq!(backoff.next_delay(), INITIAL_RECONNECT_DELAY);
+        assert_eq!(backoff.next_delay(), INITIAL_RECONNECT_DELAY * 2);
+
+        for _ in 0..20 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), MAX_RECONNECT_DELAY);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), INITIAL_RECONNECT_DELAY);
+    }
+}
@@ -0,0 +1,272 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+
+use crate::lib::cancellation::{CancelToken, JobCancelled};
+use crate::lib::effects::AudioJob;
+use crate::lib::error::WorkerError;
+use crate::lib::idempotency::{job_fingerprint, to_hex};
+use crate::lib::limits::JobLimits;
+use crate::lib::media_source::{InputSources, open_media_source};
+use crate::lib::storage::{ObjectMetadata, StorageBackend};
+
+/// Identifies how outputs are encoded. Change it whenever the same input and
+/// effect chain would produce different bytes, so stale entries stop matching.
+/// v2 outputs carry the input's tags.
+/// v3 outputs also carry the input's bext and cue/adtl chunks.
+const OUTPUT_SPEC: &str = "wav-f32-v3";
+/// Directory, or key prefix, of cached outputs.
+pub const CACHE_PREFIX: &str = "cache";
+const HASH_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// Whether a completed job was served from the output cache.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheOutcome {
+    Hit,
+    Miss,
+}
+
+/// Storage key of the cached output of `job` for this input.
+pub fn cache_key(input_digest: &str, job: &AudioJob) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(input_digest.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(job_fingerprint(job).as_bytes());
+    hasher.update(b"\n");
+    hasher.update(OUTPUT_SPEC.as_bytes());
+    format!("{CACHE_PREFIX}/{}.wav", to_hex(&hasher.finalize()))
+}
+
+/// Key of the file holding the hex SHA-256 of the cached output under `key`.
+/// Not every backend keeps object metadata, so the digest is stored next to
+/// the entry.
+fn digest_key(key: &str) -> String {
+    format!("{}.sha256", key.strip_suffix(".wav").unwrap_or(key))
+}
+
+/// The SHA-256 of the cached output under `key`, or `None` on a miss. An
+/// entry without a readable digest is a miss too.
+pub async fn lookup(
+    storage: &dyn StorageBackend,
+    key: &str,
+) -> Result<Option<[u8; 32]>, Box<dyn std::error::Error + Send + Sync>> {
+    let digest_key = digest_key(key);
+    if !storage.exists(&digest_key).await? || !storage.exists(key).await? {
+        return Ok(None);
+    }
+    let chunk = storage.get(&digest_key, 0..64).await?;
+    Ok(parse_digest(&chunk.data))
+}
+
+/// Caches the stored output at `output_key` under `key`, along with the
+/// digest in `metadata`. The digest is written last, so `lookup` never finds
+/// an entry half-written. `scratch_path` is where the digest file is staged.
+pub async fn store(
+    storage: &dyn StorageBackend,
+    output_key: &str,
+    key: &str,
+    metadata: &ObjectMetadata,
+    scratch_path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let digest = metadata.sha256.ok_or("output has no digest to cache")?;
+    storage.copy(output_key, key, metadata).await?;
+
+    tokio::fs::write(scratch_path, to_hex(&digest)).await?;
+    let stored = storage
+        .put(&digest_key(key), scratch_path, &ObjectMetadata::default())
+        .await;
+    if stored.is_err() {
+        let _ = tokio::fs::remove_file(scratch_path).await;
+    }
+    stored.map(|_| ())
+}
+
+/// Removes the entry under `key`, e.g. because its copy came out corrupt.
+pub async fn evict(
+    storage: &dyn StorageBackend,
+    key: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    storage.delete(&digest_key(key)).await?;
+    storage.delete(key).await
+}
+
+fn parse_digest(hex: &[u8]) -> Option<[u8; 32]> {
+    let hex = std::str::from_utf8(hex).ok()?;
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Hex SHA-256 of the input's content. Reads through the same source as the
+/// decoder, so the size limit and URL policy apply here too.
+pub async fn hash_input(
+    file_path: &str,
+    cancel: CancelToken,
+    limits: JobLimits,
+    inputs: &InputSources,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut source = open_media_source(file_path, limits.max_input_bytes, inputs)
+        .await
+        .map_err(|e| WorkerError::wrap(e, WorkerError::Fetch))?;
+
+    // Remote sources block on the runtime while reading.
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; HASH_CHUNK_BYTES];
+        loop {
+            if let Some(reason) = cancel.reason() {
+                return Err(JobCancelled(reason).into());
+            }
+            let read = source
+                .read(&mut buffer)
+                .map_err(|e| WorkerError::Fetch(Box::new(e)))?;
+            if read == 0 {
+                return Ok(to_hex(&hasher.finalize()));
+            }
+            hasher.update(&buffer[..read]);
+        }
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_key, hash_input, lookup, store};
+    use crate::lib::cancellation::CancelToken;
+    use crate::lib::effects::AudioJob;
+    use crate::lib::limits::JobLimits;
+    use crate::lib::media_source::InputSources;
+    use crate::lib::storage::{MemoryBackend, ObjectMetadata, StorageBackend};
+    use crate::lib::url_policy::UrlPolicy;
+    use std::fs;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn unique_temp_file() -> std::path::PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should be monotonic")
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("worker-cache-{nanos}.wav"))
+    }
+
+    fn limits() -> JobLimits {
+        JobLimits {
+            max_input_bytes: 1024,
+            max_duration: Duration::from_secs(60),
+            max_channels: 2,
+            max_sample_rate: 48_000,
+            max_effect_buffer_bytes: 1024,
+            max_corrupt_packets: 0,
+            timeout: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn keys_depend_on_input_effect_chain_and_options() {
+        let job = |gain: f32, options: &str| -> AudioJob {
+            serde_json::from_str(&format!(
+                r#"{{"job_id": "job-1", "input_path": "in.wav", "output_path": "out.wav",
+                    "effects": [{{"type": "gain", "amount": {gain}}}]{options}}}"#
+            ))
+            .expect("job should parse")
+        };
+        let plain = job(0.5, "");
+
+        assert_eq!(cache_key("abc", &plain), cache_key("abc", &job(0.5, "")));
+        assert_ne!(cache_key("abc", &plain), cache_key("abd", &plain));
+        assert_ne!(cache_key("abc", &plain), cache_key("abc", &job(0.6, "")));
+        let options = [
+            r#", "track": {"index": 1}"#,
+            r#", "metadata": {"keep": false}"#,
+            r#", "bwf": {"originator": "Desk 4"}"#,
+            r#", "markers": {"beats": true}"#,
+        ];
+        for option in options {
+            assert_ne!(
+                cache_key("abc", &plain),
+                cache_key("abc", &job(0.5, option))
+            );
+        }
+        assert!(cache_key("abc", &plain).starts_with("cache/"));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_output_digest_with_the_entry() {
+        let storage = MemoryBackend::default();
+        let output = unique_temp_file();
+        fs::write(&output, b"output").expect("output should be written");
+        storage
+            .put("processed/job-1.wav", &output, &ObjectMetadata::default())
+            .await
+            .expect("output should be stored");
+        let metadata = ObjectMetadata {
+            sha256: Some([7; 32]),
+            ..ObjectMetadata::default()
+        };
+
+        assert_eq!(lookup(&storage, "cache/abc.wav").await.ok(), Some(None));
+        store(
+            &storage,
+            "processed/job-1.wav",
+            "cache/abc.wav",
+            &metadata,
+            &unique_temp_file(),
+        )
+        .await
+        .expect("entry should be stored");
+        assert_eq!(
+            lookup(&storage, "cache/abc.wav").await.ok(),
+            Some(Some([7; 32]))
+        );
+
+        // An entry whose digest is gone, e.g. evicted, is a miss.
+        storage
+            .delete("cache/abc.sha256")
+            .await
+            .expect("digest should be deleted");
+        assert_eq!(lookup(&storage, "cache/abc.wav").await.ok(), Some(None));
+    }
+
+    #[tokio::test]
+    async fn hashes_input_content() {
+        let temp_file = unique_temp_file();
+        fs::write(&temp_file, b"abc").expect("temp file should be written");
+        let inputs = InputSources {
+            url_policy: UrlPolicy {
+                allowlist: Vec::new(),
+                object_allowlist: Vec::new(),
+                local_root: std::env::temp_dir(),
+                allow_private_networks: false,
+                max_redirects: 0,
+                connect_timeout: Duration::from_secs(1),
+                read_timeout: Duration::from_secs(1),
+            },
+            object_store: None,
+            read_ahead_bytes: 1024,
+            spool_dir: std::env::temp_dir(),
+        };
+
+        let digest = hash_input(
+            temp_file.to_str().expect("temp path should be valid UTF-8"),
+            CancelToken::new(),
+            limits(),
+            &inputs,
+        )
+        .await
+        .expect("input should be hashed");
+
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let _ = fs::remove_file(temp_file);
+    }
+}
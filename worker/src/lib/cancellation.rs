@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Cancellations for jobs we have not seen yet are forgotten after this long,
+/// so the pending set cannot grow without bound.
+const PENDING_CANCEL_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const NOT_CANCELLED: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// The worker is shutting down or lost its broker connection; the job
+    /// should be requeued and picked up again.
+    Shutdown,
+    /// A user asked for the job to be stopped; it must not run again.
+    Requested,
+    /// The job ran past its `JobLimits::timeout`.
+    TimedOut,
+}
+
+impl CancelReason {
+    fn as_u8(self) -> u8 {
+        match self {
+            CancelReason::Shutdown => 1,
+            CancelReason::Requested => 2,
+            CancelReason::TimedOut => 3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(CancelReason::Shutdown),
+            2 => Some(CancelReason::Requested),
+            3 => Some(CancelReason::TimedOut),
+            _ => None,
+        }
+    }
+}
+
+/// Cooperative cancellation flag. The decode loop checks it between packets,
+/// so a cancelled job stops at the next packet boundary. The first reason
+/// recorded wins.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicU8>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self, reason: CancelReason) {
+        let _ = self.0.compare_exchange(
+            NOT_CANCELLED,
+            reason.as_u8(),
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+
+    pub fn reason(&self) -> Option<CancelReason> {
+        CancelReason::from_u8(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// Returned by the processor when a job was stopped through its `CancelToken`.
+#[derive(Debug)]
+pub struct JobCancelled(pub CancelReason);
+
+impl fmt::Display for JobCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            CancelReason::Shutdown => write!(f, "job interrupted by worker shutdown"),
+            CancelReason::Requested => write!(f, "job cancelled"),
+            CancelReason::TimedOut => write!(f, "job timed out"),
+        }
+    }
+}
+
+impl std::error::Error for JobCancelled {}
+
+/// Tracks the jobs running on this worker so control messages and shutdown
+/// can reach them, and remembers cancellations for jobs not started yet.
+#[derive(Default)]
+pub struct JobRegistry {
+    running: Mutex<HashMap<String, CancelToken>>,
+    pending: Mutex<HashMap<String, Instant>>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Registers `job_id` as running. Returns `None` when a cancellation for it
+    /// arrived before it was picked up, in which case it must be skipped.
+    pub fn start(self: &Arc<Self>, job_id: &str) -> Option<RunningJob> {
+        if self.lock_pending().remove(job_id).is_some() {
+            return None;
+        }
+
+        let token = CancelToken::new();
+        self.lock_running()
+            .insert(job_id.to_string(), token.clone());
+
+        Some(RunningJob {
+            registry: Arc::clone(self),
+            job_id: job_id.to_string(),
+            token,
+        })
+    }
+
+    /// Cancels `job_id` if it is running here, otherwise remembers the request
+    /// so the job is skipped when it shows up.
+    pub fn cancel(&self, job_id: &str) {
+        if let Some(token) = self.lock_running().get(job_id) {
+            token.cancel(CancelReason::Requested);
+            return;
+        }
+
+        let mut pending = self.lock_pending();
+        let now = Instant::now();
+        pending.retain(|_, received_at| now.duration_since(*received_at) < PENDING_CANCEL_TTL);
+        pending.insert(job_id.to_string(), now);
+    }
+
+    pub fn cancel_all(&self, reason: CancelReason) {
+        for token in self.lock_running().values() {
+            token.cancel(reason);
+        }
+    }
+
+    fn lock_running(&self) -> std::sync::MutexGuard<'_, HashMap<String, CancelToken>> {
+        self.running
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        self.pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A job's entry in the `JobRegistry`; dropping it deregisters the job.
+pub struct RunningJob {
+    registry: Arc<JobRegistry>,
+    job_id: String,
+    token: CancelToken,
+}
+
+impl RunningJob {
+    pub fn token(&self) -> CancelToken {
+        self.token.clone()
+    }
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        let mut running = self.registry.lock_running();
+        // A redelivered duplicate may have replaced our entry; leave theirs alone.
+        if running
+            .get(&self.job_id)
+            .is_some_and(|token| Arc::ptr_eq(&token.0, &self.token.0))
+        {
+            running.remove(&self.job_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CancelReason, JobRegistry};
+
+    #[test]
+    fn cancels_running_jobs() {
+        let registry = JobRegistry::new();
+        let job = registry.start("job-1").expect("job should start");
+
+        registry.cancel("job-1");
+
+        assert_eq!(job.token().reason(), Some(CancelReason::Requested));
+    }
+
+    #[test]
+    fn skips_jobs_cancelled_before_they_start() {
+        let registry = JobRegistry::new();
+
+        registry.cancel("job-1");
+
+        assert!(registry.start("job-1").is_none());
+        assert!(registry.start("job-1").is_some());
+    }
+
+    #[test]
+    fn first_cancel_reason_wins() {
+        let registry = JobRegistry::new();
+        let job = registry.start("job-1").expect("job should start");
+
+        registry.cancel_all(CancelReason::Shutdown);
+        registry.cancel("job-1");
+
+        assert_eq!(job.token().reason(), Some(CancelReason::Shutdown));
+    }
+
+    #[test]
+    fn deregisters_finished_jobs() {
+        let registry = JobRegistry::new();
+        drop(registry.start("job-1").expect("job should start"));
+
+        registry.cancel("job-1");
+
+        assert!(registry.start("job-1").is_none());
+    }
+}
@@ -0,0 +1,405 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::lib::broker::JOB_QUEUE;
+use crate::lib::limits::JobLimits;
+use crate::lib::media_source::InputSources;
+use crate::lib::retention::{RetentionPolicy, replica_scratch_dir};
+use crate::lib::retry::RetryPolicy;
+use crate::lib::storage::{MultipartConfig, S3Config, StorageConfig, local_storage_root};
+use crate::lib::url_policy::UrlPolicy;
+
+const DEFAULT_RABBITMQ_URL: &str = "amqp://127.0.0.1:5672/%2f";
+const DEFAULT_CONSUMER_TAG: &str = "rust_worker";
+const DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS: u64 = 25;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 5_000;
+const DEFAULT_MAX_INPUT_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+const DEFAULT_MAX_DURATION_SECS: u64 = 3 * 60 * 60;
+const DEFAULT_MAX_CHANNELS: usize = 8;
+const DEFAULT_MAX_SAMPLE_RATE: u32 = 192_000;
+const DEFAULT_MAX_EFFECT_BUFFER_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_JOB_TIMEOUT_SECS: u64 = 60 * 60;
+const DEFAULT_MAX_CORRUPT_PACKETS: u32 = 32;
+const DEFAULT_INPUT_MAX_REDIRECTS: usize = 5;
+const DEFAULT_INPUT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_INPUT_READ_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_READ_AHEAD_BYTES: usize = 1024 * 1024;
+const DEFAULT_OUTPUT_URL_TTL_SECS: u64 = 60 * 60;
+/// The longest lifetime S3 accepts for a presigned URL.
+const MAX_OUTPUT_URL_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_RETENTION_SWEEP_INTERVAL_SECS: u64 = 10 * 60;
+/// Where the worker writes outputs; the API names them `processed/<job>.wav`.
+const DEFAULT_RETENTION_OUTPUT_DIR: &str = "processed";
+const SCRATCH_DIR_NAME: &str = ".scratch";
+/// Where the API stores uploaded inputs, below the storage root.
+const INPUT_DIR_NAME: &str = "inputs";
+
+/// A job queue this worker consumes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub name: String,
+    /// Maximum number of jobs from this queue processed at the same time.
+    /// Also used as the queue's channel prefetch so the broker never hands us
+    /// more than we can run.
+    pub concurrency: usize,
+    /// When set, the queue is declared as a priority queue and jobs published
+    /// with a higher AMQP `priority` are delivered first.
+    pub max_priority: Option<u8>,
+}
+
+impl QueueConfig {
+    pub fn prefetch_count(&self) -> u16 {
+        self.concurrency.min(u16::MAX as usize) as u16
+    }
+}
+
+pub struct WorkerConfig {
+    pub rabbitmq_url: String,
+    pub consumer_tag: String,
+    /// Each queue has its own concurrency limit, so a backlog of long renders
+    /// on one queue never starves the others.
+    pub queues: Vec<QueueConfig>,
+    /// How long in-flight jobs may keep running after SIGTERM before they are
+    /// cancelled and requeued.
+    pub shutdown_grace_period: Duration,
+    pub retry_policy: RetryPolicy,
+    pub limits: JobLimits,
+    pub inputs: InputSources,
+    /// Where processed outputs are written.
+    pub storage: StorageConfig,
+    /// Reuse stored outputs for jobs with the same input content and effect
+    /// chain. Off by default: it costs one extra read of every input to hash
+    /// it, which only pays off when the same inputs come back often.
+    pub output_cache: bool,
+    pub output_url_ttl: Duration,
+    pub retention: RetentionPolicy,
+    /// Shared by all replicas; each one writes into its own `scratch_dir`
+    /// below it. Lives on the storage volume by default so finished outputs
+    /// can be renamed into place.
+    pub scratch_root: PathBuf,
+    pub scratch_dir: PathBuf,
+    /// Holds the idempotency markers, which are what keeps two replicas from
+    /// running the same job. It must be one directory shared by every replica,
+    /// so with object storage outputs it has to be set to a shared volume.
+    pub marker_root: PathBuf,
+    pub verify_outputs: bool,
+}
+
+impl WorkerConfig {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let rabbitmq_url =
+            std::env::var("RABBITMQ_URL").unwrap_or_else(|_| DEFAULT_RABBITMQ_URL.into());
+
+        let consumer_tag =
+            std::env::var("WORKER_CONSUMER_TAG").unwrap_or_else(|_| DEFAULT_CONSUMER_TAG.into());
+
+        let concurrency = match std::env::var("WORKER_CONCURRENCY") {
+            Ok(raw) => parse_concurrency(&raw)?,
+            Err(_) => default_concurrency(),
+        };
+
+        let queues = match std::env::var("WORKER_QUEUES") {
+            Ok(raw) => parse_queues(&raw, concurrency)?,
+            Err(_) => vec![QueueConfig {
+                name: JOB_QUEUE.into(),
+                concurrency,
+                max_priority: None,
+            }],
+        };
+
+        let shutdown_grace_period = env_or(
+            "SHUTDOWN_GRACE_PERIOD_SECS",
+            DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS,
+        )?;
+
+        let retry_policy = RetryPolicy {
+            max_retries: env_or("JOB_MAX_RETRIES", DEFAULT_MAX_RETRIES)?,
+            base_delay: Duration::from_millis(env_or(
+                "JOB_RETRY_BASE_DELAY_MS",
+                DEFAULT_RETRY_BASE_DELAY_MS,
+            )?),
+        };
+
+        let limits = JobLimits {
+            max_input_bytes: env_or("JOB_MAX_INPUT_BYTES", DEFAULT_MAX_INPUT_BYTES)?,
+            max_duration: Duration::from_secs(env_or(
+                "JOB_MAX_DURATION_SECS",
+                DEFAULT_MAX_DURATION_SECS,
+            )?),
+            max_channels: env_or("JOB_MAX_CHANNELS", DEFAULT_MAX_CHANNELS)?,
+            max_sample_rate: env_or("JOB_MAX_SAMPLE_RATE", DEFAULT_MAX_SAMPLE_RATE)?,
+            max_effect_buffer_bytes: env_or(
+                "JOB_MAX_EFFECT_BUFFER_BYTES",
+                DEFAULT_MAX_EFFECT_BUFFER_BYTES,
+            )?,
+            max_corrupt_packets: env_or("JOB_MAX_CORRUPT_PACKETS", DEFAULT_MAX_CORRUPT_PACKETS)?,
+            timeout: Duration::from_secs(env_or("JOB_TIMEOUT_SECS", DEFAULT_JOB_TIMEOUT_SECS)?),
+        };
+
+        let url_policy = UrlPolicy {
+            allowlist: std::env::var("INPUT_URL_ALLOWLIST")
+                .map(|raw| parse_list(&raw))
+                .unwrap_or_default(),
+            object_allowlist: std::env::var("INPUT_OBJECT_ALLOWLIST")
+                .map(|raw| parse_list(&raw))
+                .unwrap_or_default(),
+            local_root: std::env::var("LOCAL_INPUT_ROOT")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| local_storage_root().join(INPUT_DIR_NAME)),
+            allow_private_networks: env_or("INPUT_ALLOW_PRIVATE_NETWORKS", false)?,
+            max_redirects: env_or("INPUT_MAX_REDIRECTS", DEFAULT_INPUT_MAX_REDIRECTS)?,
+            connect_timeout: Duration::from_secs(env_or(
+                "INPUT_CONNECT_TIMEOUT_SECS",
+                DEFAULT_INPUT_CONNECT_TIMEOUT_SECS,
+            )?),
+            read_timeout: Duration::from_secs(env_or(
+                "INPUT_READ_TIMEOUT_SECS",
+                DEFAULT_INPUT_READ_TIMEOUT_SECS,
+            )?),
+        };
+
+        // Malformed upload settings are a typo to fix rather than a storage
+        // outage, so they stop the worker like the settings above.
+        MultipartConfig::from_env()?;
+
+        // A broken storage configuration fails each job with a clear error
+        // rather than stopping the worker.
+        let storage = StorageConfig::from_env().unwrap_or_else(|e| {
+            eprintln!("Storage is unavailable: {}", e);
+            StorageConfig::Unavailable {
+                reason: e.to_string(),
+            }
+        });
+
+        // `s3://` and `r2://` inputs reuse the output store's credentials when
+        // it is S3-compatible, and fall back to the R2 variables otherwise.
+        let object_store = match &storage {
+            StorageConfig::S3(config) => Some(config.clone()),
+            _ => S3Config::r2_from_env().ok(),
+        };
+
+        // Zero disables the TTL and the quota.
+        let retention = RetentionPolicy {
+            output_dirs: std::env::var("RETENTION_OUTPUT_DIRS")
+                .map(|raw| parse_list(&raw))
+                .unwrap_or_else(|_| vec![DEFAULT_RETENTION_OUTPUT_DIR.to_string()]),
+            output_ttl: Some(Duration::from_secs(env_or("OUTPUT_RETENTION_SECS", 0)?))
+                .filter(|ttl| !ttl.is_zero()),
+            quota_bytes: Some(env_or("STORAGE_QUOTA_BYTES", 0)?).filter(|quota| *quota > 0),
+            sweep_interval: Duration::from_secs(
+                env_or(
+                    "RETENTION_SWEEP_INTERVAL_SECS",
+                    DEFAULT_RETENTION_SWEEP_INTERVAL_SECS,
+                )?
+                .max(1),
+            ),
+            // Live jobs touch their scratch file constantly and never outlast
+            // the job timeout.
+            scratch_ttl: limits.timeout * 2,
+        };
+
+        let scratch_root = std::env::var("WORKER_SCRATCH_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| local_storage_root().join(SCRATCH_DIR_NAME));
+        let scratch_dir = replica_scratch_dir(&scratch_root);
+
+        let marker_root = match (std::env::var("JOB_MARKER_ROOT"), &storage) {
+            (Ok(raw), _) => PathBuf::from(raw),
+            (Err(_), StorageConfig::S3(_)) => {
+                return Err(
+                    "JOB_MARKER_ROOT must name a volume shared by all worker replicas \
+                            when outputs go to object storage"
+                        .into(),
+                );
+            }
+            (Err(_), StorageConfig::Local { root }) => root.clone(),
+            (Err(_), _) => local_storage_root(),
+        };
+
+        Ok(Self {
+            rabbitmq_url,
+            consumer_tag,
+            queues,
+            shutdown_grace_period: Duration::from_secs(shutdown_grace_period),
+            retry_policy,
+            limits,
+            inputs: InputSources {
+                url_policy,
+                object_store,
+                read_ahead_bytes: env_or("HTTP_READ_AHEAD_BYTES", DEFAULT_READ_AHEAD_BYTES)?.max(1),
+                spool_dir: scratch_dir.clone(),
+            },
+            storage,
+            output_cache: env_or("OUTPUT_CACHE_ENABLED", false)?,
+            output_url_ttl: Duration::from_secs(
+                env_or("OUTPUT_URL_TTL_SECS", DEFAULT_OUTPUT_URL_TTL_SECS)?
+                    .clamp(1, MAX_OUTPUT_URL_TTL_SECS),
+            ),
+            retention,
+            scratch_root,
+            scratch_dir,
+            marker_root,
+            verify_outputs: env_or("OUTPUT_VERIFY", false)?,
+        })
+    }
+}
+
+/// Parses `WORKER_QUEUES`, a comma separated list of
+/// `name[:concurrency[:max_priority]]`, e.g.
+/// `audio_jobs.preview:4:10,audio_jobs.full:1`. Queues without an explicit
+/// concurrency get `default_concurrency`.
+///
+/// A queue's priority setting must match how it was first declared, so leave
+/// it unset for `audio_jobs`, which the API declares without one.
+fn parse_queues(
+    raw: &str,
+    default_concurrency: usize,
+) -> Result<Vec<QueueConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut queues: Vec<QueueConfig> = Vec::new();
+
+    for entry in raw
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let mut parts = entry.split(':').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        if name.is_empty() {
+            return Err(format!("invalid WORKER_QUEUES entry: {entry}").into());
+        }
+        if queues.iter().any(|queue| queue.name == name) {
+            return Err(format!("queue {name} is listed twice in WORKER_QUEUES").into());
+        }
+
+        let concurrency = match parts.next() {
+            Some(raw) if !raw.is_empty() => parse_concurrency(raw)?,
+            _ => default_concurrency,
+        };
+        let max_priority = match parts.next() {
+            Some(raw) if !raw.is_empty() => match raw.parse::<u8>() {
+                Ok(value) if value > 0 => Some(value),
+                _ => return Err(format!("invalid max priority for queue {name}: {raw}").into()),
+            },
+            _ => None,
+        };
+        if parts.next().is_some() {
+            return Err(format!("invalid WORKER_QUEUES entry: {entry}").into());
+        }
+
+        queues.push(QueueConfig {
+            name: name.to_string(),
+            concurrency,
+            max_priority,
+        });
+    }
+
+    if queues.is_empty() {
+        return Err("WORKER_QUEUES does not name any queue".into());
+    }
+
+    Ok(queues)
+}
+
+fn parse_concurrency(raw: &str) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    match raw.trim().parse::<usize>() {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Err(format!("invalid WORKER_CONCURRENCY: {raw}").into()),
+    }
+}
+
+fn parse_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The value of the environment variable `name`, or `default` when it is
+/// unset. A value that does not parse is an error naming the variable, so a
+/// typo stops the worker instead of quietly running with the default.
+pub fn env_or<T: std::str::FromStr>(
+    name: &str,
+    default: T,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    match std::env::var(name) {
+        Ok(raw) => parse_setting(name, &raw),
+        Err(_) => Ok(default),
+    }
+}
+
+fn parse_setting<T: std::str::FromStr>(
+    name: &str,
+    raw: &str,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    raw.trim()
+        .parse::<T>()
+        .map_err(|_| format!("invalid {name}: {raw}").into())
+}
+
+fn default_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|cores| cores.get())
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueueConfig, parse_concurrency, parse_queues, parse_setting};
+
+    #[test]
+    fn parses_positive_concurrency() {
+        assert_eq!(parse_concurrency(" 4 ").expect("4 should parse"), 4);
+    }
+
+    #[test]
+    fn rejects_zero_or_garbage_concurrency() {
+        assert!(parse_concurrency("0").is_err());
+        assert!(parse_concurrency("many").is_err());
+    }
+
+    #[test]
+    fn names_the_variable_of_a_malformed_setting() {
+        let timeout: u64 = parse_setting("JOB_TIMEOUT_SECS", " 30 ").expect("30 should parse");
+        assert_eq!(timeout, 30);
+
+        let error = parse_setting::<u64>("JOB_TIMEOUT_SECS", "30s")
+            .expect_err("30s should not parse")
+            .to_string();
+        assert_eq!(error, "invalid JOB_TIMEOUT_SECS: 30s");
+        assert!(parse_setting::<bool>("OUTPUT_VERIFY", "yes").is_err());
+    }
+
+    #[test]
+    fn parses_queue_list_with_defaults() {
+        let queues = parse_queues("audio_jobs.preview:4:10, audio_jobs.full", 2)
+            .expect("queue list should parse");
+
+        assert_eq!(
+            queues,
+            vec![
+                QueueConfig {
+                    name: "audio_jobs.preview".into(),
+                    concurrency: 4,
+                    max_priority: Some(10),
+                },
+                QueueConfig {
+                    name: "audio_jobs.full".into(),
+                    concurrency: 2,
+                    max_priority: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_queue_lists() {
+        assert!(parse_queues("", 1).is_err());
+        assert!(parse_queues("a,a", 1).is_err());
+        assert!(parse_queues("a:0", 1).is_err());
+        assert!(parse_queues("a:1:0", 1).is_err());
+        assert!(parse_queues("a:1:300", 1).is_err());
+        assert!(parse_queues(":2", 1).is_err());
+    }
+}
@@ -0,0 +1,46 @@
+use lapin::message::Delivery;
+use serde::Deserialize;
+
+use crate::lib::cancellation::JobRegistry;
+
+/// Messages broadcast on the control exchange to every worker replica.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ControlMessage {
+    Cancel { job_id: String },
+}
+
+pub fn handle_control_delivery(registry: &JobRegistry, delivery: &Delivery) {
+    let message = match serde_json::from_slice::<ControlMessage>(&delivery.data) {
+        Ok(message) => message,
+        Err(e) => {
+            eprintln!("Ignoring unreadable control message: {}", e);
+            return;
+        }
+    };
+
+    match message {
+        ControlMessage::Cancel { job_id } => {
+            println!("Cancellation requested for job {}", job_id);
+            registry.cancel(&job_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ControlMessage;
+
+    #[test]
+    fn parses_cancel_messages() {
+        let message: ControlMessage = serde_json::from_str(r#"{"type":"cancel","job_id":"job-1"}"#)
+            .expect("cancel message should parse");
+
+        assert_eq!(
+            message,
+            ControlMessage::Cancel {
+                job_id: "job-1".into()
+            }
+        );
+    }
+}
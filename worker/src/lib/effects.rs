@@ -1,6 +1,12 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Debug)]
+use crate::lib::broadcast::BroadcastFields;
+use crate::lib::error::WorkerError;
+use crate::lib::markers::MarkerOptions;
+use crate::lib::tags::MetadataEdit;
+use crate::lib::tracks::{TrackSelector, Tracks};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum EffectConfig {
     Bitcrusher {
@@ -35,10 +41,12 @@ impl EffectConfig {
                 delay_ms,
                 feedback,
                 mix,
-            } => {
-                let delay_frames = (sample_rate as f32 * (delay_ms as f32 / 1000.0)) as usize;
-                Box::new(SimpleDelay::new(delay_frames, feedback, mix, channels))
-            }
+            } => Box::new(SimpleDelay::new(
+                delay_frames(delay_ms, sample_rate),
+                feedback,
+                mix,
+                channels,
+            )),
             EffectConfig::Gain { amount } => Box::new(Gain { amount }),
             EffectConfig::Tremolo { frequency, depth } => {
                 Box::new(Tremolo::new(frequency, depth, sample_rate, channels))
@@ -49,14 +57,118 @@ impl EffectConfig {
             }
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EffectConfig::Bitcrusher { .. } => "bitcrusher",
+            EffectConfig::Delay { .. } => "delay",
+            EffectConfig::Gain { .. } => "gain",
+            EffectConfig::Tremolo { .. } => "tremolo",
+            EffectConfig::Distortion { .. } => "distortion",
+            EffectConfig::Lowpass { .. } => "lowpass",
+        }
+    }
+
+    /// Rejects settings that would produce NaNs, silence or a runaway
+    /// feedback loop instead of audio.
+    pub fn validate(&self) -> Result<(), WorkerError> {
+        let problem = match *self {
+            EffectConfig::Bitcrusher { bits } => (!(1..=32).contains(&bits))
+                .then(|| format!("bits must be between 1 and 32, got {bits}")),
+            EffectConfig::Delay { feedback, mix, .. } => {
+                if feedback.is_nan() || feedback.abs() >= 1.0 {
+                    Some(format!(
+                        "feedback must be between -1 and 1 (exclusive), got {feedback}"
+                    ))
+                } else {
+                    check_mix(mix)
+                }
+            }
+            EffectConfig::Gain { amount } => (!amount.is_finite())
+                .then(|| format!("amount must be a finite number, got {amount}")),
+            EffectConfig::Tremolo { frequency, depth } => {
+                if !(frequency >= 0.0 && frequency.is_finite()) {
+                    Some(format!(
+                        "frequency must be a non-negative number, got {frequency}"
+                    ))
+                } else {
+                    (!(0.0..=1.0).contains(&depth))
+                        .then(|| format!("depth must be between 0 and 1, got {depth}"))
+                }
+            }
+            EffectConfig::Distortion { drive, mix } => {
+                if !(drive > 0.0 && drive.is_finite()) {
+                    Some(format!("drive must be a positive number, got {drive}"))
+                } else {
+                    check_mix(mix)
+                }
+            }
+            EffectConfig::Lowpass { cutoff } => (!(cutoff > 0.0 && cutoff.is_finite()))
+                .then(|| format!("cutoff must be a positive frequency, got {cutoff}")),
+        };
+
+        match problem {
+            Some(reason) => Err(WorkerError::EffectParam {
+                effect: self.name(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Bytes `into_effect` would allocate for sample buffers. Computed without
+    /// allocating, so oversized settings can be rejected up front.
+    pub fn buffer_bytes(&self, sample_rate: usize, channels: usize) -> usize {
+        match self {
+            EffectConfig::Delay { delay_ms, .. } => delay_frames(*delay_ms, sample_rate)
+                .saturating_mul(channels.max(1))
+                .saturating_mul(std::mem::size_of::<f32>()),
+            _ => 0,
+        }
+    }
+}
+
+fn check_mix(mix: f32) -> Option<String> {
+    (!(0.0..=1.0).contains(&mix)).then(|| format!("mix must be between 0 and 1, got {mix}"))
 }
 
-#[derive(Deserialize, Debug)]
+fn delay_frames(delay_ms: usize, sample_rate: usize) -> usize {
+    (sample_rate as f32 * (delay_ms as f32 / 1000.0)) as usize
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct AudioJob {
     pub job_id: String,
     pub input_path: String,
     pub output_path: String,
     pub effects: Vec<EffectConfig>,
+    /// Audio track to process. The first one when unset.
+    #[serde(default)]
+    pub track: Option<TrackSelector>,
+    /// Processes every audio track, each into its own output.
+    #[serde(default)]
+    pub all_tracks: bool,
+    /// Tags and artwork of the output; the input's when unset.
+    #[serde(default)]
+    pub metadata: MetadataEdit,
+    /// Broadcast WAV (`bext`) fields of the output.
+    #[serde(default)]
+    pub bwf: BroadcastFields,
+    /// Markers written as `cue ` points into the output.
+    #[serde(default)]
+    pub markers: MarkerOptions,
+}
+
+impl AudioJob {
+    pub fn tracks(&self) -> Result<Tracks, WorkerError> {
+        match (&self.track, self.all_tracks) {
+            (Some(_), true) => Err(WorkerError::InvalidJob(
+                "track and all_tracks cannot be combined".into(),
+            )),
+            (None, true) => Ok(Tracks::All),
+            (track, false) => Ok(Tracks::One(track.clone())),
+        }
+    }
 }
 
 pub trait AudioEffect {
@@ -124,7 +236,7 @@ impl AudioEffect for SimpleDelay {
             self.buffer[self.write_pos] = *sample + (delayed_sample * self.feedback);
 
             // Mix original signal with delayed signal
-            *sample = *sample + (delayed_sample * self.mix);
+            *sample += delayed_sample * self.mix;
 
             // Move circular buffer head
             self.write_pos = (self.write_pos + 1) % self.buffer.len();
@@ -231,3 +343,40 @@ impl AudioEffect for LowPass {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EffectConfig;
+
+    #[test]
+    fn rejects_out_of_range_settings() {
+        let invalid = [
+            EffectConfig::Bitcrusher { bits: 0 },
+            EffectConfig::Delay {
+                delay_ms: 100,
+                feedback: 1.0,
+                mix: 0.5,
+            },
+            EffectConfig::Gain { amount: f32::NAN },
+            EffectConfig::Distortion {
+                drive: 2.0,
+                mix: 1.5,
+            },
+            EffectConfig::Lowpass { cutoff: 0.0 },
+        ];
+        for effect in invalid {
+            let error = effect.validate().expect_err("settings should be rejected");
+            assert_eq!(error.code(), "invalid_effect_param");
+        }
+
+        assert!(
+            EffectConfig::Delay {
+                delay_ms: 100,
+                feedback: 0.5,
+                mix: 0.5,
+            }
+            .validate()
+            .is_ok()
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::lib::retry::{FailureKind, classify, find_cause};
+
+/// Why a job failed, as far as the worker can tell. Each variant maps onto the
+/// error code reported in the job's `failed` status and decides whether the
+/// job is worth retrying.
+#[derive(Debug)]
+pub enum WorkerError {
+    /// The input is corrupt or could not be demuxed.
+    Decode(Box<dyn Error + Send + Sync>),
+    /// The input has no audio track, or one in a codec we cannot decode.
+    UnsupportedCodec(String),
+    /// Opening or reading the input failed.
+    Fetch(Box<dyn Error + Send + Sync>),
+    /// Storing an output or reading it back failed.
+    Storage(Box<dyn Error + Send + Sync>),
+    /// The worker is misconfigured, e.g. a storage variable is missing.
+    Config(String),
+    /// The job message does not describe a job we can run.
+    InvalidJob(String),
+    /// An effect setting is out of range.
+    EffectParam {
+        effect: &'static str,
+        reason: String,
+    },
+}
+
+impl WorkerError {
+    /// Error code reported in the job's `failed` status.
+    pub fn code(&self) -> &'static str {
+        match self {
+            WorkerError::Decode(_) => "decode_failed",
+            WorkerError::UnsupportedCodec(_) => "unsupported_codec",
+            WorkerError::Fetch(_) => "fetch_failed",
+            WorkerError::Storage(_) => "storage_failed",
+            WorkerError::Config(_) => "worker_misconfigured",
+            WorkerError::InvalidJob(_) => "invalid_job",
+            WorkerError::EffectParam { .. } => "invalid_effect_param",
+        }
+    }
+
+    pub fn retryable(&self) -> bool {
+        match self {
+            // Retrying helps when the input could not be read, not when it
+            // was read and is broken; the cause tells which.
+            WorkerError::Decode(source) | WorkerError::Fetch(source) => {
+                classify(source.as_ref()) == FailureKind::Transient
+            }
+            // Storage failures are outages unless the store refused outright.
+            WorkerError::Storage(source) => find_cause::<io::Error>(source.as_ref())
+                .is_none_or(|io| classify(io) == FailureKind::Transient),
+            WorkerError::UnsupportedCodec(_)
+            | WorkerError::Config(_)
+            | WorkerError::InvalidJob(_)
+            | WorkerError::EffectParam { .. } => false,
+        }
+    }
+
+    /// Wraps `error` with `variant`, unless it already carries a
+    /// `WorkerError` that says more precisely what went wrong.
+    pub fn wrap(
+        error: Box<dyn Error + Send + Sync>,
+        variant: fn(Box<dyn Error + Send + Sync>) -> WorkerError,
+    ) -> Box<dyn Error + Send + Sync> {
+        if find_cause::<WorkerError>(error.as_ref()).is_some() {
+            return error;
+        }
+        Box::new(variant(error))
+    }
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkerError::Decode(source) => write!(f, "cannot decode input: {source}"),
+            WorkerError::UnsupportedCodec(what) => write!(f, "unsupported input: {what}"),
+            WorkerError::Fetch(source) => write!(f, "cannot read input: {source}"),
+            WorkerError::Storage(source) => write!(f, "cannot store output: {source}"),
+            WorkerError::Config(reason) => write!(f, "worker misconfigured: {reason}"),
+            WorkerError::InvalidJob(reason) => write!(f, "invalid job: {reason}"),
+            WorkerError::EffectParam { effect, reason } => {
+                write!(f, "invalid {effect} settings: {reason}")
+            }
+        }
+    }
+}
+
+impl Error for WorkerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WorkerError::Decode(source)
+            | WorkerError::Fetch(source)
+            | WorkerError::Storage(source) => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<symphonia::core::errors::Error> for WorkerError {
+    fn from(error: symphonia::core::errors::Error) -> Self {
+        match error {
+            symphonia::core::errors::Error::Unsupported(what) => {
+                WorkerError::UnsupportedCodec(what.to_string())
+            }
+            // Symphonia reports failures to read the source as I/O errors.
+            symphonia::core::errors::Error::IoError(error) => WorkerError::Fetch(Box::new(error)),
+            error => WorkerError::Decode(Box::new(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WorkerError;
+    use crate::lib::limits::LimitExceeded;
+    use crate::lib::retry::{FailureKind, classify};
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn classifies_by_variant_and_cause() {
+        let outage = WorkerError::Storage(Box::new(io::Error::other("connection reset")));
+        let refused = WorkerError::Storage(Box::new(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "403",
+        )));
+        let missing = WorkerError::Fetch(Box::new(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no such file",
+        )));
+
+        assert!(outage.retryable());
+        assert!(!refused.retryable());
+        assert!(!missing.retryable());
+        assert!(!WorkerError::UnsupportedCodec("opus".into()).retryable());
+        assert_eq!(classify(&outage), FailureKind::Transient);
+        assert_eq!(classify(&missing), FailureKind::Permanent);
+    }
+
+    #[test]
+    fn maps_symphonia_errors() {
+        let unsupported =
+            WorkerError::from(symphonia::core::errors::Error::Unsupported("codec: opus"));
+        let corrupt = WorkerError::from(symphonia::core::errors::Error::DecodeError("bad frame"));
+
+        assert_eq!(unsupported.code(), "unsupported_codec");
+        assert_eq!(corrupt.code(), "decode_failed");
+        assert!(!corrupt.retryable());
+    }
+
+    #[test]
+    fn wrap_keeps_the_most_specific_error() {
+        let invalid = WorkerError::wrap(
+            Box::new(WorkerError::InvalidJob("bad output path".into())),
+            WorkerError::Storage,
+        );
+        let limit = WorkerError::wrap(
+            Box::new(LimitExceeded::Timeout(Duration::from_secs(1))),
+            WorkerError::Fetch,
+        );
+
+        assert_eq!(
+            invalid.downcast_ref::<WorkerError>().map(WorkerError::code),
+            Some("invalid_job")
+        );
+        assert_eq!(
+            limit.downcast_ref::<WorkerError>().map(WorkerError::code),
+            Some("fetch_failed")
+        );
+        assert_eq!(classify(limit.as_ref()), FailureKind::Permanent);
+    }
+}
@@ -0,0 +1,488 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+use crate::lib::broadcast::BroadcastFields;
+use crate::lib::effects::{AudioJob, EffectConfig};
+use crate::lib::markers::MarkerOptions;
+use crate::lib::status::TrackOutput;
+use crate::lib::storage::StorageResult;
+use crate::lib::tags::MetadataEdit;
+
+/// Markers live next to the outputs so every replica sharing the storage
+/// volume sees the same ledger.
+pub const MARKER_DIR: &str = ".jobs";
+/// A lock whose holder stopped refreshing it for this long is considered
+/// abandoned (the worker crashed) and may be taken over.
+const LOCK_STALE_AFTER: Duration = Duration::from_secs(120);
+const LOCK_HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// Hex SHA-256 of the effect chain as serialized JSON. Two deliveries of the
+/// same job with different settings get different keys.
+pub fn fingerprint(effects: &[EffectConfig]) -> String {
+    let canonical = serde_json::to_vec(effects).unwrap_or_default();
+    to_hex(&Sha256::digest(canonical))
+}
+
+/// Hex SHA-256 of everything about a job that shapes its outputs: the effect
+/// chain, and the options that are set. Two deliveries of the same job that
+/// ask for different outputs get different keys.
+pub fn job_fingerprint(job: &AudioJob) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(fingerprint(&job.effects).as_bytes());
+
+    let mut option = |set: bool, value: serde_json::Result<Vec<u8>>| {
+        if set {
+            hasher.update(b"\n");
+            hasher.update(value.unwrap_or_default());
+        }
+    };
+    option(job.track.is_some(), serde_json::to_vec(&job.track));
+    option(job.all_tracks, serde_json::to_vec(&job.all_tracks));
+    option(
+        job.metadata != MetadataEdit::default(),
+        serde_json::to_vec(&job.metadata),
+    );
+    option(
+        job.bwf != BroadcastFields::default(),
+        serde_json::to_vec(&job.bwf),
+    );
+    option(
+        job.markers != MarkerOptions::default(),
+        serde_json::to_vec(&job.markers),
+    );
+    to_hex(&hasher.finalize())
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// What a completion marker records.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompletedJob {
+    /// The output, or the first one when every track was rendered. Flattened
+    /// so markers written before per-track outputs existed still read.
+    #[serde(flatten)]
+    pub output: StorageResult,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tracks: Vec<TrackOutput>,
+}
+
+pub enum Claim {
+    /// We hold the lock and must run the job.
+    Acquired(JobLock),
+    /// A previous delivery finished; its result can be re-published as is.
+    AlreadyCompleted(CompletedJob),
+    /// Another delivery of the same job is running right now.
+    InProgress,
+}
+
+pub struct IdempotencyStore {
+    root: PathBuf,
+}
+
+impl IdempotencyStore {
+    pub fn new(storage_root: &Path) -> Self {
+        Self {
+            root: storage_root.join(MARKER_DIR),
+        }
+    }
+
+    /// Creates the marker directory and checks it can be written, so a
+    /// missing or read-only volume stops the worker at startup instead of
+    /// failing every job.
+    pub fn ensure_writable(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.root)?;
+        let probe = self.root.join(format!(".probe-{}", std::process::id()));
+        File::create(&probe)?.sync_all()?;
+        fs::remove_file(probe)
+    }
+
+    pub fn claim(&self, job_id: &str, fingerprint: &str) -> io::Result<Claim> {
+        fs::create_dir_all(&self.root)?;
+        let key = marker_key(job_id, fingerprint);
+        let done_path = self.root.join(format!("{key}.done"));
+        let lock_path = self.root.join(format!("{key}.lock"));
+
+        if let Some(result) = read_done_marker(&done_path)? {
+            return Ok(Claim::AlreadyCompleted(result));
+        }
+
+        match File::create_new(&lock_path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if !is_stale(&lock_path)? || !take_over(&lock_path)? {
+                    return Ok(Claim::InProgress);
+                }
+                eprintln!("Took over stale lock {}", lock_path.display());
+                if let Err(e) = File::create_new(&lock_path) {
+                    if e.kind() == io::ErrorKind::AlreadyExists {
+                        return Ok(Claim::InProgress);
+                    }
+                    return Err(e);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+
+        Ok(Claim::Acquired(JobLock::new(lock_path, done_path)))
+    }
+
+    /// Drops the completion marker of a job whose outputs are gone, e.g.
+    /// deleted by retention, so the next claim runs it again.
+    pub fn forget(&self, job_id: &str, fingerprint: &str) -> io::Result<()> {
+        let key = marker_key(job_id, fingerprint);
+        match fs::remove_file(self.root.join(format!("{key}.done"))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Held while a job runs. Dropping it without calling `complete` releases the
+/// lock so a later redelivery can try again. `complete` syncs to disk, so
+/// async callers run it on a blocking thread.
+pub struct JobLock {
+    lock_path: PathBuf,
+    done_path: PathBuf,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl JobLock {
+    fn new(lock_path: PathBuf, done_path: PathBuf) -> Self {
+        // Tests claim outside a runtime; the heartbeat only matters for real jobs.
+        let heartbeat = tokio::runtime::Handle::try_current().ok().map(|handle| {
+            let lock_path = lock_path.clone();
+            handle.spawn(async move {
+                loop {
+                    tokio::time::sleep(LOCK_HEARTBEAT).await;
+                    let path = lock_path.clone();
+                    let touched = tokio::task::spawn_blocking(move || touch(&path))
+                        .await
+                        .unwrap_or_else(|e| Err(io::Error::other(e)));
+                    if let Err(e) = touched {
+                        eprintln!("Failed to refresh job lock {}: {}", lock_path.display(), e);
+                    }
+                }
+            })
+        });
+
+        Self {
+            lock_path,
+            done_path,
+            heartbeat,
+        }
+    }
+
+    /// Records the stored output so redeliveries re-publish it instead of
+    /// processing the job again.
+    pub fn complete(self, result: &CompletedJob) -> io::Result<()> {
+        let payload = serde_json::to_vec(result).map_err(io::Error::other)?;
+        let temp_path = self.done_path.with_extension("done.tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.done_path)
+    }
+}
+
+impl Drop for JobLock {
+    fn drop(&mut self) {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+        let _ = fs::remove_file(&self.lock_path);
+    }
+}
+
+fn read_done_marker(path: &Path) -> io::Result<Option<CompletedJob>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes).ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_stale(lock_path: &Path) -> io::Result<bool> {
+    let modified = match fs::metadata(lock_path) {
+        Ok(metadata) => metadata.modified()?,
+        // Released between our create attempt and now.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    };
+
+    Ok(SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|age| age > LOCK_STALE_AFTER))
+}
+
+/// Moves a stale lock out of the way. Removing it outright would race: a
+/// replica that saw the same stale lock could delete the fresh one another
+/// replica just created in its place. The lock is renamed to a name of our
+/// own first, so only one replica gets it, and put back if what it got
+/// turns out to be live. Returns whether the lock is gone.
+fn take_over(lock_path: &Path) -> io::Result<bool> {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let mut name = lock_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}-{nanos}.stale", std::process::id()));
+    let taken = lock_path.with_file_name(name);
+
+    match fs::rename(lock_path, &taken) {
+        Ok(()) => {}
+        // Another replica took it over first.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    if is_stale(&taken)? {
+        fs::remove_file(&taken)?;
+        return Ok(true);
+    }
+
+    // A fresh lock created after we checked; give it back to its holder.
+    let restored = fs::hard_link(&taken, lock_path);
+    fs::remove_file(&taken)?;
+    match restored {
+        Ok(()) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn touch(path: &Path) -> io::Result<()> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Job ids come from the API as UUIDs, but anything that is not a plain file
+/// name is hashed so it can never escape the marker directory.
+fn marker_key(job_id: &str, fingerprint: &str) -> String {
+    let safe_id = if !job_id.is_empty()
+        && job_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        job_id.to_string()
+    } else {
+        to_hex(&Sha256::digest(job_id.as_bytes()))
+    };
+
+    format!("{safe_id}-{}", &fingerprint[..fingerprint.len().min(16)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Claim, CompletedJob, IdempotencyStore, LOCK_STALE_AFTER, MARKER_DIR, fingerprint,
+        job_fingerprint, marker_key, take_over,
+    };
+    use crate::lib::effects::{AudioJob, EffectConfig};
+    use crate::lib::storage::StorageResult;
+    use std::fs::{self, File};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn unique_temp_dir(name: &str) -> std::path::PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should be monotonic")
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("worker-idempotency-{name}-{nanos}"))
+    }
+
+    fn result() -> CompletedJob {
+        CompletedJob {
+            output: StorageResult {
+                output_key: "processed/job-1.wav".into(),
+                output_url: None,
+                output_size_bytes: 9,
+                output_etag: None,
+                output_sha256: None,
+            },
+            tracks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn fingerprint_depends_on_effect_settings() {
+        let quiet = fingerprint(&[EffectConfig::Gain { amount: 0.5 }]);
+        let loud = fingerprint(&[EffectConfig::Gain { amount: 2.0 }]);
+
+        assert_ne!(quiet, loud);
+        assert_eq!(quiet, fingerprint(&[EffectConfig::Gain { amount: 0.5 }]));
+    }
+
+    #[test]
+    fn job_fingerprint_depends_on_output_options() {
+        let job = |options: &str| -> AudioJob {
+            serde_json::from_str(&format!(
+                r#"{{"job_id": "job-1", "input_path": "in.wav", "output_path": "out.wav",
+                    "effects": []{options}}}"#
+            ))
+            .expect("job should parse")
+        };
+
+        assert_eq!(job_fingerprint(&job("")), job_fingerprint(&job("")));
+        assert_ne!(
+            job_fingerprint(&job(r#", "track": {"index": 0}"#)),
+            job_fingerprint(&job(r#", "track": {"index": 1}"#))
+        );
+        assert_ne!(
+            job_fingerprint(&job("")),
+            job_fingerprint(&job(r#", "all_tracks": true"#))
+        );
+    }
+
+    #[test]
+    fn refuses_marker_roots_it_cannot_write() {
+        let root = unique_temp_dir("writable");
+        IdempotencyStore::new(&root)
+            .ensure_writable()
+            .expect("a fresh directory should be writable");
+        assert_eq!(
+            fs::read_dir(root.join(MARKER_DIR))
+                .expect("marker dir should exist")
+                .count(),
+            0
+        );
+
+        let file = root.join("not-a-dir");
+        fs::write(&file, b"").expect("file should be written");
+        assert!(IdempotencyStore::new(&file).ensure_writable().is_err());
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn detects_concurrent_duplicates_and_replays_completed_jobs() {
+        let root = unique_temp_dir("claim");
+        let store = IdempotencyStore::new(&root);
+
+        let Claim::Acquired(lock) = store.claim("job-1", "abc").expect("claim should work") else {
+            panic!("first delivery should acquire the lock");
+        };
+        assert!(matches!(
+            store.claim("job-1", "abc").expect("claim should work"),
+            Claim::InProgress
+        ));
+
+        lock.complete(&result())
+            .expect("completion should be recorded");
+
+        match store.claim("job-1", "abc").expect("claim should work") {
+            Claim::AlreadyCompleted(stored) => assert_eq!(stored, result()),
+            _ => panic!("redelivery should replay the stored result"),
+        }
+        assert!(matches!(
+            store
+                .claim("job-1", "other-effects")
+                .expect("claim should work"),
+            Claim::Acquired(_)
+        ));
+
+        // The outputs expired; the next delivery runs the job again.
+        store
+            .forget("job-1", "abc")
+            .expect("marker should be dropped");
+        store
+            .forget("job-1", "abc")
+            .expect("a missing marker is fine");
+        assert!(matches!(
+            store.claim("job-1", "abc").expect("claim should work"),
+            Claim::Acquired(_)
+        ));
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn reads_markers_without_track_outputs() {
+        let marker = serde_json::to_vec(&result().output).expect("result should serialize");
+
+        assert_eq!(
+            serde_json::from_slice::<CompletedJob>(&marker).expect("marker should parse"),
+            result()
+        );
+    }
+
+    #[test]
+    fn releases_lock_when_job_fails() {
+        let root = unique_temp_dir("release");
+        let store = IdempotencyStore::new(&root);
+
+        drop(store.claim("job-1", "abc").expect("claim should work"));
+
+        assert!(matches!(
+            store.claim("job-1", "abc").expect("claim should work"),
+            Claim::Acquired(_)
+        ));
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn takes_over_stale_locks() {
+        let root = unique_temp_dir("stale");
+        let store = IdempotencyStore::new(&root);
+        let lock_path = root
+            .join(".jobs")
+            .join(format!("{}.lock", marker_key("job-1", "abc")));
+        fs::create_dir_all(lock_path.parent().expect("lock has a parent"))
+            .expect("marker dir should be created");
+        File::create(&lock_path)
+            .expect("lock should be created")
+            .set_modified(SystemTime::now() - LOCK_STALE_AFTER * 2)
+            .expect("mtime should be set");
+
+        assert!(matches!(
+            store.claim("job-1", "abc").expect("claim should work"),
+            Claim::Acquired(_)
+        ));
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn gives_back_locks_that_turned_live() {
+        let root = unique_temp_dir("takeover");
+        fs::create_dir_all(&root).expect("root should be created");
+        let lock_path = root.join("job-1.lock");
+
+        // Another replica replaced the stale lock after we saw it.
+        File::create(&lock_path).expect("lock should be created");
+        assert!(!take_over(&lock_path).expect("takeover should work"));
+        assert!(lock_path.exists());
+        assert_eq!(
+            fs::read_dir(&root).expect("root should be listed").count(),
+            1
+        );
+
+        File::create(&lock_path)
+            .expect("lock should be opened")
+            .set_modified(SystemTime::now() - LOCK_STALE_AFTER * 2)
+            .expect("mtime should be set");
+        assert!(take_over(&lock_path).expect("takeover should work"));
+        assert!(!take_over(&lock_path).expect("takeover should work"));
+        assert_eq!(
+            fs::read_dir(&root).expect("root should be listed").count(),
+            0
+        );
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn hashes_unsafe_job_ids() {
+        assert!(!marker_key("../../etc/passwd", "abc").contains('/'));
+        assert!(marker_key("job-1", "abc").starts_with("job-1-"));
+    }
+}
@@ -0,0 +1,678 @@
+use lapin::Channel;
+use lapin::message::Delivery;
+use lapin::options::{BasicAckOptions, BasicNackOptions};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::lib::audio_processor::{AudioSummary, decode_audio_file};
+use crate::lib::broker::{dead_letter_queue, publish};
+use crate::lib::cache::{self, CacheOutcome, cache_key, hash_input};
+use crate::lib::cancellation::{CancelReason, CancelToken, JobCancelled, JobRegistry};
+use crate::lib::effects::AudioJob;
+use crate::lib::error::WorkerError;
+use crate::lib::idempotency::{
+    Claim, CompletedJob, IdempotencyStore, fingerprint, job_fingerprint, to_hex,
+};
+use crate::lib::limits::{JobLimits, LimitExceeded};
+use crate::lib::media_source::InputSources;
+use crate::lib::retry::{
+    FailureKind, RetryDecision, RetryPolicy, classify, find_cause, republish_properties,
+    retry_count,
+};
+use crate::lib::status::{
+    AudioAnalysis, JobStatusMessage, ProgressSender, TrackOutput, publish_status,
+    spawn_progress_publisher,
+};
+use crate::lib::storage::{
+    ObjectMetadata, StorageBackend, copy_output, persist_output, verify_stored,
+};
+use crate::lib::tracks::Tracks;
+use crate::lib::url_policy::InputRejected;
+
+/// A finished job. `analysis` is only known when the input was decoded.
+struct JobOutput {
+    completed: CompletedJob,
+    cache: Option<CacheOutcome>,
+    analysis: Option<AudioAnalysis>,
+}
+
+enum JobFailure {
+    /// A user cancelled the job; it must not run again.
+    Cancelled,
+    /// The worker is shutting down; the job is requeued.
+    Interrupted,
+    Failed {
+        kind: FailureKind,
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+/// Everything a job needs besides its delivery. `queue` is the job queue the
+/// delivery came from; retries and dead-letters are routed relative to it.
+#[derive(Clone)]
+pub struct JobContext {
+    pub channel: Channel,
+    pub queue: String,
+    pub registry: Arc<JobRegistry>,
+    pub retry_policy: RetryPolicy,
+    pub limits: JobLimits,
+    pub inputs: Arc<InputSources>,
+    pub storage: Arc<dyn StorageBackend>,
+    pub output_cache: bool,
+    /// Lifetime of the download URLs attached to completed jobs.
+    pub output_url_ttl: Duration,
+    /// This replica's scratch directory, where outputs are written before
+    /// they are stored.
+    pub scratch_dir: PathBuf,
+    /// Shared by every replica; see `WorkerConfig::marker_root`.
+    pub marker_root: PathBuf,
+    /// Re-read every stored output and compare it with its digest.
+    pub verify_outputs: bool,
+}
+
+/// Runs a single delivery to completion: decode, persist, publish status and
+/// ack/nack. Errors returned from here are broker-level failures only.
+pub async fn handle_delivery(
+    ctx: &JobContext,
+    delivery: Delivery,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let channel = &ctx.channel;
+    let job = match parse_job(&delivery.data) {
+        Ok(job) => job,
+        Err(e) => {
+            eprintln!("Dead-lettering unreadable job message: {}", e);
+            let error = e.to_string();
+            dead_letter(ctx, &delivery, &error).await?;
+            if let Some(job_id) = extract_job_id(&delivery.data) {
+                publish_status(channel, &JobStatusMessage::failed(job_id, e.code(), error)).await?;
+            }
+            delivery.ack(BasicAckOptions::default()).await?;
+            return Ok(());
+        }
+    };
+    println!("Received job from {}: {:?}", ctx.queue, job);
+
+    let job_id = job.job_id.clone();
+
+    let fingerprint = job_fingerprint(&job);
+    let mut claim = claim_job(&ctx.marker_root, &job_id, &fingerprint, false).await;
+    if let Ok(Claim::AlreadyCompleted(completed)) = &claim
+        && !outputs_exist(ctx, completed).await
+    {
+        println!("Outputs of job {} are gone, running it again", job_id);
+        claim = claim_job(&ctx.marker_root, &job_id, &fingerprint, true).await;
+    }
+    let lock = match claim {
+        Ok(Claim::Acquired(lock)) => lock,
+        Ok(Claim::AlreadyCompleted(mut completed)) => {
+            println!("Job {} already completed, re-publishing its result", job_id);
+            // The URLs recorded with the result may have expired by now.
+            let outputs = std::iter::once(&mut completed.output)
+                .chain(completed.tracks.iter_mut().map(|track| &mut track.output));
+            for output in outputs {
+                match ctx
+                    .storage
+                    .presign(&output.output_key, ctx.output_url_ttl)
+                    .await
+                {
+                    Ok(Some(url)) => output.output_url = Some(url),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Failed to refresh output URL of job {}: {}", job_id, e),
+                }
+            }
+            let status = JobStatusMessage {
+                tracks: completed.tracks,
+                ..JobStatusMessage::completed(job_id, completed.output)
+            };
+            publish_status(channel, &status).await?;
+            delivery.ack(BasicAckOptions::default()).await?;
+            return Ok(());
+        }
+        Ok(Claim::InProgress) => {
+            println!(
+                "Job {} is already running, deferring duplicate delivery",
+                job_id
+            );
+            defer_duplicate(ctx, &delivery).await?;
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("Failed to claim job {}: {}", job_id, e);
+            handle_failure(ctx, &delivery, &job_id, FailureKind::Transient, &e).await?;
+            delivery.ack(BasicAckOptions::default()).await?;
+            return Ok(());
+        }
+    };
+
+    let Some(running) = ctx.registry.start(&job_id) else {
+        println!(
+            "Skipping job {}, it was cancelled before it started",
+            job_id
+        );
+        publish_status(channel, &JobStatusMessage::cancelled(job_id)).await?;
+        delivery.ack(BasicAckOptions::default()).await?;
+        return Ok(());
+    };
+
+    publish_status(channel, &JobStatusMessage::received(job_id.clone())).await?;
+    publish_status(channel, &JobStatusMessage::processing(job_id.clone(), 0.0)).await?;
+
+    let (progress, progress_task) = spawn_progress_publisher(channel.clone(), job_id.clone());
+    let token = running.token();
+    let timeout = ctx.limits.timeout;
+    let outcome =
+        match tokio::time::timeout(timeout, run_job(ctx, job, token.clone(), progress)).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                // Dropping the future stops storage uploads, and multipart ones
+                // abort themselves; the decode thread notices the token at its
+                // next packet and cleans up after itself.
+                token.cancel(CancelReason::TimedOut);
+                Err(timed_out(timeout))
+            }
+        };
+    progress_task.abort();
+    let _ = progress_task.await;
+    drop(running);
+
+    match outcome {
+        Ok(output) => {
+            println!("Processing succeeded for job {}", job_id);
+            let completed = output.completed.clone();
+            let recorded = tokio::task::spawn_blocking(move || lock.complete(&completed))
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+            if let Err(e) = recorded {
+                eprintln!("Failed to record completion of job {}: {}", job_id, e);
+            }
+            let status = JobStatusMessage {
+                cache: output.cache,
+                analysis: output.analysis,
+                tracks: output.completed.tracks,
+                ..JobStatusMessage::completed(job_id, output.completed.output)
+            };
+            publish_status(channel, &status).await?;
+            delivery.ack(BasicAckOptions::default()).await?;
+        }
+        Err(JobFailure::Cancelled) => {
+            println!("Job {} cancelled", job_id);
+            publish_status(channel, &JobStatusMessage::cancelled(job_id)).await?;
+            delivery.ack(BasicAckOptions::default()).await?;
+        }
+        Err(JobFailure::Interrupted) => {
+            println!("Job {} interrupted, requeueing it", job_id);
+            delivery
+                .nack(BasicNackOptions {
+                    requeue: true,
+                    ..BasicNackOptions::default()
+                })
+                .await?;
+        }
+        Err(JobFailure::Failed { kind, error }) => {
+            eprintln!("Error processing audio for job {}: {}", job_id, error);
+            handle_failure(ctx, &delivery, &job_id, kind, error.as_ref()).await?;
+            delivery.ack(BasicAckOptions::default()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_job(
+    ctx: &JobContext,
+    job: AudioJob,
+    cancel: CancelToken,
+    progress: ProgressSender,
+) -> Result<JobOutput, JobFailure> {
+    let limits = ctx.limits;
+    let output_path = Path::new(&job.output_path);
+
+    let invalid = |error: WorkerError| JobFailure::Failed {
+        kind: FailureKind::Permanent,
+        error: Box::new(error),
+    };
+    let tracks = job.tracks().map_err(invalid)?;
+    for effect in &job.effects {
+        effect.validate().map_err(invalid)?;
+    }
+    job.metadata.validate().map_err(invalid)?;
+    job.bwf.validate().map_err(invalid)?;
+    job.markers.validate().map_err(invalid)?;
+
+    // Only single outputs are cached.
+    let cached_key = if ctx.output_cache && tracks != Tracks::All {
+        let digest = hash_input(&job.input_path, cancel.clone(), limits, &ctx.inputs)
+            .await
+            .map_err(|error| decode_failure(error, limits.timeout))?;
+        Some(cache_key(&digest, &job))
+    } else {
+        None
+    };
+
+    // Storing is not interruptible, so a cancellation that came in while the
+    // input was read or decoded is honoured before anything is stored.
+    let check_cancelled = || match cancel.reason() {
+        Some(reason) => Err(cancelled(reason, limits.timeout)),
+        None => Ok(()),
+    };
+
+    if let Some(key) = &cached_key {
+        match cache::lookup(ctx.storage.as_ref(), key).await {
+            Ok(Some(digest)) => {
+                println!("Output cache hit for job {} ({})", job.job_id, key);
+                check_cancelled()?;
+                let mut metadata = output_metadata(&job, None);
+                metadata.sha256 = Some(digest);
+                let stored = copy_output(
+                    ctx.storage.as_ref(),
+                    key,
+                    output_path,
+                    &metadata,
+                    ctx.output_url_ttl,
+                )
+                .await
+                .map_err(storage_failure)?;
+                if ctx.verify_outputs
+                    && let Err(error) =
+                        verify_stored(ctx.storage.as_ref(), &stored.output_key, &digest).await
+                {
+                    // The retry decodes instead of copying the same bytes.
+                    if let Err(e) = cache::evict(ctx.storage.as_ref(), key).await {
+                        eprintln!("Failed to evict cache entry {}: {}", key, e);
+                    }
+                    return Err(storage_failure(error));
+                }
+                return Ok(JobOutput {
+                    completed: CompletedJob {
+                        output: stored,
+                        tracks: Vec::new(),
+                    },
+                    cache: Some(CacheOutcome::Hit),
+                    analysis: None,
+                });
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Output cache lookup failed for {}: {}", key, e),
+        }
+    }
+
+    let scratch_path = ctx.scratch_dir.join(scratch_file_name(&job.job_id));
+    let summaries = match decode_audio_file(
+        &job,
+        &scratch_path,
+        cancel.clone(),
+        progress,
+        limits,
+        &ctx.inputs,
+    )
+    .await
+    {
+        Ok(summaries) => summaries,
+        Err(error) => {
+            let _ = std::fs::remove_file(&scratch_path);
+            return Err(decode_failure(error, limits.timeout));
+        }
+    };
+    // A retry decodes again, so half-stored outputs are of no use.
+    let discard_scratch = |from: usize| {
+        for summary in &summaries[from..] {
+            let _ = std::fs::remove_file(tracks.output_path(&scratch_path, summary.track_index));
+        }
+    };
+
+    let mut outputs = Vec::with_capacity(summaries.len());
+    for (n, summary) in summaries.iter().enumerate() {
+        if let Err(failure) = check_cancelled() {
+            discard_scratch(n);
+            return Err(failure);
+        }
+        let stored = persist_output(
+            ctx.storage.as_ref(),
+            &tracks.output_path(&scratch_path, summary.track_index),
+            &tracks.output_path(output_path, summary.track_index),
+            &output_metadata(&job, Some(summary)),
+            ctx.output_url_ttl,
+        )
+        .await
+        .map_err(|error| {
+            discard_scratch(n);
+            storage_failure(error)
+        })?;
+
+        if ctx.verify_outputs
+            && let Err(error) =
+                verify_stored(ctx.storage.as_ref(), &stored.output_key, &summary.sha256).await
+        {
+            discard_scratch(n + 1);
+            return Err(storage_failure(error));
+        }
+        outputs.push(TrackOutput {
+            output: stored,
+            analysis: AudioAnalysis::from(summary),
+        });
+    }
+
+    // Track selection never comes back empty, so there is a first output.
+    let first = outputs[0].clone();
+    let completed = CompletedJob {
+        output: first.output,
+        tracks: if tracks == Tracks::All {
+            outputs
+        } else {
+            Vec::new()
+        },
+    };
+    let analysis = Some(first.analysis);
+    let Some(key) = cached_key else {
+        return Ok(JobOutput {
+            completed,
+            cache: None,
+            analysis,
+        });
+    };
+    // The job already succeeded; a missing cache entry only costs a re-run.
+    let metadata = output_metadata(&job, Some(&summaries[0]));
+    if let Err(e) = cache::store(
+        ctx.storage.as_ref(),
+        &completed.output.output_key,
+        &key,
+        &metadata,
+        &scratch_path.with_extension("sha256"),
+    )
+    .await
+    {
+        eprintln!("Failed to cache output of job {}: {}", job.job_id, e);
+    }
+    Ok(JobOutput {
+        completed,
+        cache: Some(CacheOutcome::Miss),
+        analysis,
+    })
+}
+
+/// Job ids are hashed so they can never escape the scratch directory.
+/// Claims the job, first dropping its completion marker with `forget`.
+/// Markers are created and synced on disk, which blocks.
+async fn claim_job(
+    marker_root: &Path,
+    job_id: &str,
+    fingerprint: &str,
+    forget: bool,
+) -> io::Result<Claim> {
+    let store = IdempotencyStore::new(marker_root);
+    let (job_id, fingerprint) = (job_id.to_string(), fingerprint.to_string());
+    tokio::task::spawn_blocking(move || {
+        if forget {
+            store.forget(&job_id, &fingerprint)?;
+        }
+        store.claim(&job_id, &fingerprint)
+    })
+    .await
+    .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+/// Whether the outputs a completion marker points to are still stored.
+/// When storage cannot tell, they are assumed to be.
+async fn outputs_exist(ctx: &JobContext, completed: &CompletedJob) -> bool {
+    let keys = std::iter::once(&completed.output)
+        .chain(completed.tracks.iter().map(|track| &track.output))
+        .map(|output| &output.output_key);
+    for key in keys {
+        match ctx.storage.exists(key).await {
+            Ok(true) => {}
+            Ok(false) => return false,
+            Err(e) => eprintln!("Failed to check output {}: {}", key, e),
+        }
+    }
+    true
+}
+
+fn scratch_file_name(job_id: &str) -> String {
+    format!("{}.wav", to_hex(&Sha256::digest(job_id.as_bytes())))
+}
+
+/// Object metadata describing how an output was produced. `summary` is only
+/// known when the job actually decoded its input.
+fn output_metadata(job: &AudioJob, summary: Option<&AudioSummary>) -> ObjectMetadata {
+    let mut metadata = ObjectMetadata::default();
+    metadata.user.insert("job-id".into(), job.job_id.clone());
+    metadata
+        .user
+        .insert("effects-digest".into(), fingerprint(&job.effects));
+    if let Some(summary) = summary {
+        metadata.sha256 = Some(summary.sha256);
+        metadata.user.insert(
+            "duration-seconds".into(),
+            format!("{:.3}", summary.duration_secs()),
+        );
+        metadata
+            .user
+            .insert("sample-rate".into(), summary.sample_rate.to_string());
+    }
+    metadata
+}
+
+/// Maps an error from reading or decoding the input onto a job outcome.
+fn decode_failure(
+    error: Box<dyn std::error::Error + Send + Sync>,
+    timeout: std::time::Duration,
+) -> JobFailure {
+    match error.downcast_ref::<JobCancelled>() {
+        Some(JobCancelled(reason)) => cancelled(*reason, timeout),
+        None => JobFailure::Failed {
+            kind: classify(error.as_ref()),
+            error,
+        },
+    }
+}
+
+/// Maps an error from storing an output onto a job outcome. Unless the store
+/// refused the request outright, these are outages worth retrying.
+fn storage_failure(error: Box<dyn std::error::Error + Send + Sync>) -> JobFailure {
+    let error = WorkerError::wrap(error, WorkerError::Storage);
+    JobFailure::Failed {
+        kind: classify(error.as_ref()),
+        error,
+    }
+}
+
+fn cancelled(reason: CancelReason, timeout: std::time::Duration) -> JobFailure {
+    match reason {
+        CancelReason::Requested => JobFailure::Cancelled,
+        CancelReason::Shutdown => JobFailure::Interrupted,
+        CancelReason::TimedOut => timed_out(timeout),
+    }
+}
+
+fn timed_out(timeout: std::time::Duration) -> JobFailure {
+    JobFailure::Failed {
+        kind: FailureKind::Permanent,
+        error: Box::new(LimitExceeded::Timeout(timeout)),
+    }
+}
+
+/// Schedules a delayed retry, or moves the job to the dead-letter queue and
+/// reports it as failed once retrying is pointless.
+async fn handle_failure(
+    ctx: &JobContext,
+    delivery: &Delivery,
+    job_id: &str,
+    kind: FailureKind,
+    error: &(dyn std::error::Error + Send + Sync + 'static),
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let retry_policy = &ctx.retry_policy;
+    let retries = retry_count(&delivery.properties);
+
+    match retry_policy.decide(&ctx.queue, kind, retries) {
+        RetryDecision::Retry { attempt, queue } => {
+            println!(
+                "Retrying job {} in {:?} (attempt {}/{})",
+                job_id,
+                retry_policy.delay_for(attempt),
+                attempt,
+                retry_policy.max_retries
+            );
+            let properties = republish_properties(&delivery.properties, attempt, &[]);
+            publish(&ctx.channel, "", &queue, &delivery.data, properties).await
+        }
+        RetryDecision::DeadLetter => {
+            let code = failure_code(error, kind);
+            let error = error.to_string();
+            dead_letter(ctx, delivery, &error).await?;
+            publish_status(
+                &ctx.channel,
+                &JobStatusMessage::failed(job_id.to_string(), code, error),
+            )
+            .await
+        }
+    }
+}
+
+fn failure_code(error: &(dyn std::error::Error + 'static), kind: FailureKind) -> &'static str {
+    if let Some(limit) = find_cause::<LimitExceeded>(error) {
+        return limit.code();
+    }
+    if find_cause::<InputRejected>(error).is_some() {
+        return "input_rejected";
+    }
+    if let Some(error) = find_cause::<WorkerError>(error) {
+        return error.code();
+    }
+    match kind {
+        FailureKind::Permanent => "processing_failed",
+        FailureKind::Transient => "retries_exhausted",
+    }
+}
+
+/// Parks a duplicate in the first retry queue without counting it as a retry,
+/// so it is looked at again once the running copy has finished (and its result
+/// can be replayed) or its lock has gone stale.
+async fn defer_duplicate(
+    ctx: &JobContext,
+    delivery: &Delivery,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if ctx.retry_policy.max_retries == 0 {
+        delivery
+            .nack(BasicNackOptions {
+                requeue: true,
+                ..BasicNackOptions::default()
+            })
+            .await?;
+        return Ok(());
+    }
+
+    let properties =
+        republish_properties(&delivery.properties, retry_count(&delivery.properties), &[]);
+    publish(
+        &ctx.channel,
+        "",
+        &ctx.retry_policy.retry_queue(&ctx.queue, 1),
+        &delivery.data,
+        properties,
+    )
+    .await?;
+    delivery.ack(BasicAckOptions::default()).await?;
+    Ok(())
+}
+
+async fn dead_letter(
+    ctx: &JobContext,
+    delivery: &Delivery,
+    error: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let properties = republish_properties(
+        &delivery.properties,
+        retry_count(&delivery.properties),
+        &[("x-error", error)],
+    );
+    publish(
+        &ctx.channel,
+        "",
+        &dead_letter_queue(&ctx.queue),
+        &delivery.data,
+        properties,
+    )
+    .await
+}
+
+fn parse_job(data: &[u8]) -> Result<AudioJob, WorkerError> {
+    let data = std::str::from_utf8(data)
+        .map_err(|e| WorkerError::InvalidJob(format!("payload is not UTF-8: {e}")))?;
+    serde_json::from_str(data).map_err(|e| WorkerError::InvalidJob(e.to_string()))
+}
+
+/// Best-effort lookup of `job_id` in a payload that failed to parse as a job,
+/// so the API can still be told the job failed.
+fn extract_job_id(data: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(data).ok()?;
+    value.get("job_id")?.as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobFailure, cancelled, extract_job_id, failure_code, parse_job, storage_failure};
+    use crate::lib::cancellation::CancelReason;
+    use crate::lib::error::WorkerError;
+    use crate::lib::retry::FailureKind;
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn rejects_malformed_job_payloads() {
+        assert!(parse_job(b"{not json").is_err());
+        assert_eq!(
+            parse_job(br#"{"job_id":"job-1"}"#)
+                .map_err(|e| e.code())
+                .err(),
+            Some("invalid_job")
+        );
+    }
+
+    #[test]
+    fn reports_worker_errors_by_code() {
+        let JobFailure::Failed { kind, error } =
+            storage_failure(Box::new(io::Error::other("connection reset")))
+        else {
+            panic!("storage errors should fail the job");
+        };
+        assert_eq!(kind, FailureKind::Transient);
+        assert_eq!(failure_code(error.as_ref(), kind), "storage_failed");
+
+        let missing = WorkerError::Config("S3_BUCKET is not set".into());
+        let JobFailure::Failed { kind, error } = storage_failure(Box::new(missing)) else {
+            panic!("storage errors should fail the job");
+        };
+        assert_eq!(kind, FailureKind::Permanent);
+        assert_eq!(failure_code(error.as_ref(), kind), "worker_misconfigured");
+    }
+
+    #[test]
+    fn maps_cancel_reasons_to_outcomes() {
+        let timeout = Duration::from_secs(60);
+        assert!(matches!(
+            cancelled(CancelReason::Requested, timeout),
+            JobFailure::Cancelled
+        ));
+        assert!(matches!(
+            cancelled(CancelReason::Shutdown, timeout),
+            JobFailure::Interrupted
+        ));
+        let JobFailure::Failed { kind, error } = cancelled(CancelReason::TimedOut, timeout) else {
+            panic!("timed out jobs should fail");
+        };
+        assert_eq!(kind, FailureKind::Permanent);
+        assert_eq!(failure_code(error.as_ref(), kind), "timeout");
+    }
+
+    #[test]
+    fn extracts_job_id_from_unparseable_jobs() {
+        assert_eq!(
+            extract_job_id(br#"{"job_id":"job-1","effects":"oops"}"#),
+            Some("job-1".to_string())
+        );
+        assert_eq!(extract_job_id(b"{not json"), None);
+    }
+}
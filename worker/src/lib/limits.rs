@@ -0,0 +1,212 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use crate::lib::effects::EffectConfig;
+
+/// Guardrails applied to every job. Inputs and effect settings come straight
+/// from users, so nothing they control may size a download, a buffer or the
+/// job's running time without a cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobLimits {
+    pub max_input_bytes: u64,
+    pub max_duration: Duration,
+    pub max_channels: usize,
+    pub max_sample_rate: u32,
+    /// Memory a single effect may allocate for its buffers (e.g. a delay line).
+    pub max_effect_buffer_bytes: usize,
+    /// Packets the decoder may reject before the input is considered corrupt.
+    pub max_corrupt_packets: u32,
+    /// Wall-clock budget for decoding, processing and storing one job.
+    pub timeout: Duration,
+}
+
+impl JobLimits {
+    pub fn check_format(&self, sample_rate: u32, channels: usize) -> Result<(), LimitExceeded> {
+        if channels > self.max_channels {
+            return Err(LimitExceeded::Channels {
+                channels,
+                max: self.max_channels,
+            });
+        }
+        if sample_rate > self.max_sample_rate {
+            return Err(LimitExceeded::SampleRate {
+                sample_rate,
+                max: self.max_sample_rate,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_duration(&self, frames: u64, sample_rate: u32) -> Result<(), LimitExceeded> {
+        if frames > self.max_frames(sample_rate) {
+            return Err(LimitExceeded::Duration {
+                seconds: frames / u64::from(sample_rate.max(1)),
+                max: self.max_duration,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_effects(
+        &self,
+        effects: &[EffectConfig],
+        sample_rate: u32,
+        channels: usize,
+    ) -> Result<(), LimitExceeded> {
+        for effect in effects {
+            let bytes = effect.buffer_bytes(sample_rate as usize, channels);
+            if bytes > self.max_effect_buffer_bytes {
+                return Err(LimitExceeded::EffectMemory {
+                    effect: effect.name(),
+                    bytes,
+                    max: self.max_effect_buffer_bytes,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn max_frames(&self, sample_rate: u32) -> u64 {
+        self.max_duration
+            .as_secs()
+            .saturating_mul(u64::from(sample_rate))
+    }
+}
+
+/// A job broke one of its `JobLimits`. These never succeed on retry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitExceeded {
+    InputSize {
+        bytes: u64,
+        max: u64,
+    },
+    Duration {
+        seconds: u64,
+        max: Duration,
+    },
+    Channels {
+        channels: usize,
+        max: usize,
+    },
+    SampleRate {
+        sample_rate: u32,
+        max: u32,
+    },
+    EffectMemory {
+        effect: &'static str,
+        bytes: usize,
+        max: usize,
+    },
+    Timeout(Duration),
+}
+
+impl LimitExceeded {
+    /// Error code reported in the job's `failed` status.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LimitExceeded::InputSize { .. } => "input_too_large",
+            LimitExceeded::Duration { .. } => "duration_too_long",
+            LimitExceeded::Channels { .. } => "too_many_channels",
+            LimitExceeded::SampleRate { .. } => "sample_rate_too_high",
+            LimitExceeded::EffectMemory { .. } => "effect_too_large",
+            LimitExceeded::Timeout(_) => "timeout",
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::InputSize { bytes, max } => {
+                write!(f, "input is {bytes} bytes, the limit is {max} bytes")
+            }
+            LimitExceeded::Duration { seconds, max } => write!(
+                f,
+                "audio is longer than {seconds}s, the limit is {}s",
+                max.as_secs()
+            ),
+            LimitExceeded::Channels { channels, max } => {
+                write!(f, "audio has {channels} channels, the limit is {max}")
+            }
+            LimitExceeded::SampleRate { sample_rate, max } => {
+                write!(f, "sample rate is {sample_rate} Hz, the limit is {max} Hz")
+            }
+            LimitExceeded::EffectMemory { effect, bytes, max } => write!(
+                f,
+                "{effect} effect needs {bytes} bytes of buffer, the limit is {max} bytes"
+            ),
+            LimitExceeded::Timeout(timeout) => {
+                write!(f, "job did not finish within {}s", timeout.as_secs())
+            }
+        }
+    }
+}
+
+impl Error for LimitExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobLimits, LimitExceeded};
+    use crate::lib::effects::EffectConfig;
+    use std::time::Duration;
+
+    fn limits() -> JobLimits {
+        JobLimits {
+            max_input_bytes: 1_000,
+            max_duration: Duration::from_secs(60),
+            max_channels: 2,
+            max_sample_rate: 48_000,
+            max_effect_buffer_bytes: 1024 * 1024,
+            max_corrupt_packets: 0,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn rejects_inputs_beyond_limits() {
+        let limits = limits();
+
+        assert!(limits.check_format(48_000, 2).is_ok());
+        assert_eq!(
+            limits.check_format(48_000, 6).unwrap_err().code(),
+            "too_many_channels"
+        );
+        assert_eq!(
+            limits.check_format(96_000, 2).unwrap_err().code(),
+            "sample_rate_too_high"
+        );
+        assert!(limits.check_duration(60 * 48_000, 48_000).is_ok());
+        assert_eq!(
+            limits
+                .check_duration(61 * 48_000, 48_000)
+                .unwrap_err()
+                .code(),
+            "duration_too_long"
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_delay_buffers() {
+        let limits = limits();
+        let short = EffectConfig::Delay {
+            delay_ms: 500,
+            feedback: 0.5,
+            mix: 0.5,
+        };
+        let huge = EffectConfig::Delay {
+            delay_ms: usize::MAX,
+            feedback: 0.5,
+            mix: 0.5,
+        };
+
+        assert!(limits.check_effects(&[short], 48_000, 2).is_ok());
+        assert!(matches!(
+            limits.check_effects(&[huge], 48_000, 2),
+            Err(LimitExceeded::EffectMemory {
+                effect: "delay",
+                ..
+            })
+        ));
+    }
+}
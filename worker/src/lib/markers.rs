@@ -0,0 +1,410 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+use crate::lib::error::WorkerError;
+use crate::lib::riff::Chunk;
+
+/// Frames per analysis window.
+const WINDOW_FRAMES: usize = 1024;
+/// Windows of history a beat is compared against, about a second at 44.1 kHz.
+const BEAT_HISTORY_WINDOWS: usize = 43;
+/// How much louder than its history a window must be to count as a beat.
+const BEAT_SENSITIVITY: f64 = 1.5;
+/// Mean square energy below which nothing counts as a beat.
+const BEAT_FLOOR: f64 = 1e-6;
+/// Beats closer together than this (in seconds) are one beat.
+const MIN_BEAT_SPACING: f64 = 0.25;
+
+/// A marker or region in the output, in frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CuePoint {
+    pub frame: u64,
+    pub length: Option<u64>,
+    pub label: String,
+}
+
+/// A marker as jobs set it and the analysis reports it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub position_seconds: f64,
+    /// Makes the marker a region.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length_seconds: Option<f64>,
+    #[serde(default)]
+    pub label: String,
+}
+
+impl Marker {
+    pub fn from_cue(cue: &CuePoint, sample_rate: u32) -> Self {
+        let seconds = |frames: u64| frames as f64 / f64::from(sample_rate.max(1));
+        Self {
+            position_seconds: seconds(cue.frame),
+            length_seconds: cue.length.map(seconds),
+            label: cue.label.clone(),
+        }
+    }
+
+    fn to_cue(&self, sample_rate: u32) -> CuePoint {
+        let frames = |seconds: f64| (seconds * f64::from(sample_rate)).round() as u64;
+        CuePoint {
+            frame: frames(self.position_seconds),
+            length: self.length_seconds.map(frames),
+            label: self.label.clone(),
+        }
+    }
+}
+
+/// Markers a job writes into its output, e.g.
+/// `{"points": [{"position_seconds": 12.5, "label": "Intro"}], "beats": true}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MarkerOptions {
+    #[serde(default)]
+    pub points: Vec<Marker>,
+    /// Marks where sound resumes after a silence.
+    #[serde(default)]
+    pub silence: Option<SilenceSplit>,
+    /// Marks detected beats.
+    #[serde(default)]
+    pub beats: bool,
+}
+
+impl MarkerOptions {
+    pub fn validate(&self) -> Result<(), WorkerError> {
+        for marker in &self.points {
+            let valid = |seconds: f64| seconds.is_finite() && seconds >= 0.0;
+            if !valid(marker.position_seconds) || !marker.length_seconds.is_none_or(valid) {
+                return Err(WorkerError::InvalidJob(format!(
+                    "marker {:?} needs a finite, non-negative position and length",
+                    marker.label
+                )));
+            }
+        }
+        if let Some(silence) = &self.silence
+            && !(silence.threshold_db.is_finite() && silence.threshold_db <= 0.0)
+        {
+            return Err(WorkerError::InvalidJob(format!(
+                "silence threshold must be at most 0 dB, got {}",
+                silence.threshold_db
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SilenceSplit {
+    /// Level below which audio counts as silent.
+    #[serde(default = "default_threshold_db")]
+    pub threshold_db: f32,
+    /// Shorter silences are pauses, not splits.
+    #[serde(default = "default_min_silence_ms")]
+    pub min_silence_ms: u32,
+}
+
+fn default_threshold_db() -> f32 {
+    -50.0
+}
+
+fn default_min_silence_ms() -> u32 {
+    500
+}
+
+/// Finds markers in rendered audio and merges them with the given ones.
+pub struct MarkerDetector {
+    channels: usize,
+    sample_rate: u32,
+    options: MarkerOptions,
+    energy: f64,
+    window_len: usize,
+    window_start: u64,
+    silent_since: Option<u64>,
+    history: VecDeque<f64>,
+    last_beat: Option<u64>,
+    splits: usize,
+    beats: usize,
+    found: Vec<CuePoint>,
+}
+
+impl MarkerDetector {
+    pub fn new(options: &MarkerOptions, sample_rate: u32, channels: usize) -> Self {
+        Self {
+            channels: channels.max(1),
+            sample_rate,
+            options: options.clone(),
+            energy: 0.0,
+            window_len: 0,
+            window_start: 0,
+            silent_since: None,
+            history: VecDeque::with_capacity(BEAT_HISTORY_WINDOWS + 1),
+            last_beat: None,
+            splits: 0,
+            beats: 0,
+            found: Vec::new(),
+        }
+    }
+
+    /// Feeds interleaved output samples.
+    pub fn process(&mut self, samples: &[f32]) {
+        if self.options.silence.is_none() && !self.options.beats {
+            return;
+        }
+        for frame in samples.chunks_exact(self.channels) {
+            self.energy += frame
+                .iter()
+                .map(|&s| f64::from(s) * f64::from(s))
+                .sum::<f64>();
+            self.window_len += 1;
+            if self.window_len == WINDOW_FRAMES {
+                self.end_window();
+            }
+        }
+    }
+
+    fn end_window(&mut self) {
+        let mean_square = self.energy / (WINDOW_FRAMES * self.channels) as f64;
+        let start = self.window_start;
+        self.energy = 0.0;
+        self.window_len = 0;
+        self.window_start += WINDOW_FRAMES as u64;
+
+        if let Some(silence) = &self.options.silence {
+            let level_db = 10.0 * mean_square.max(1e-20).log10();
+            if level_db < f64::from(silence.threshold_db) {
+                self.silent_since.get_or_insert(start);
+            } else if let Some(since) = self.silent_since.take() {
+                let min_frames =
+                    u64::from(silence.min_silence_ms) * u64::from(self.sample_rate) / 1000;
+                // Silence at the very start is not a split.
+                if since > 0 && start - since >= min_frames {
+                    self.splits += 1;
+                    self.found.push(CuePoint {
+                        frame: start,
+                        length: None,
+                        label: format!("Split {}", self.splits),
+                    });
+                }
+            }
+        }
+
+        if self.options.beats {
+            if self.history.len() == BEAT_HISTORY_WINDOWS {
+                let average = self.history.iter().sum::<f64>() / BEAT_HISTORY_WINDOWS as f64;
+                let spacing = (MIN_BEAT_SPACING * f64::from(self.sample_rate)) as u64;
+                let spaced = self.last_beat.is_none_or(|last| start - last >= spacing);
+                if mean_square > BEAT_SENSITIVITY * average && mean_square > BEAT_FLOOR && spaced {
+                    self.beats += 1;
+                    self.found.push(CuePoint {
+                        frame: start,
+                        length: None,
+                        label: format!("Beat {}", self.beats),
+                    });
+                    self.last_beat = Some(start);
+                }
+                self.history.pop_front();
+            }
+            self.history.push_back(mean_square);
+        }
+    }
+
+    /// The input's markers, the job's and the detected ones, in position
+    /// order. Markers past the end of an output of `frames` are dropped.
+    pub fn finish(self, input: &[CuePoint], frames: u64) -> Vec<CuePoint> {
+        let given = self
+            .options
+            .points
+            .iter()
+            .map(|marker| marker.to_cue(self.sample_rate));
+        let mut cues: Vec<CuePoint> = input.iter().cloned().chain(given).collect();
+        cues.retain(|cue| {
+            let inside = cue.frame <= frames;
+            if !inside {
+                eprintln!(
+                    "Dropping marker {:?} at frame {}, past the end of the output",
+                    cue.label, cue.frame
+                );
+            }
+            inside
+        });
+        cues.extend(self.found);
+        cues.sort_by_key(|cue| cue.frame);
+        cues
+    }
+}
+
+/// `cue ` and `LIST adtl` chunks for `cues`, with ids in list order. Nothing
+/// when there are no cues.
+pub fn cue_chunks(cues: &[CuePoint]) -> Vec<Chunk> {
+    if cues.is_empty() {
+        return Vec::new();
+    }
+
+    let mut points = (cues.len() as u32).to_le_bytes().to_vec();
+    let mut notes = Vec::new();
+    for (n, cue) in cues.iter().enumerate() {
+        let id = (n as u32 + 1).to_le_bytes();
+        let frame = u32::try_from(cue.frame).unwrap_or(u32::MAX).to_le_bytes();
+        points.extend_from_slice(&id);
+        points.extend_from_slice(&frame);
+        points.extend_from_slice(b"data");
+        points.extend_from_slice(&[0; 8]);
+        points.extend_from_slice(&frame);
+
+        if !cue.label.is_empty() {
+            let mut label = id.to_vec();
+            label.extend_from_slice(cue.label.as_bytes());
+            label.push(0);
+            notes.push(Chunk::new(b"labl", label));
+        }
+        if let Some(length) = cue.length {
+            let mut region = id.to_vec();
+            region.extend_from_slice(&u32::try_from(length).unwrap_or(u32::MAX).to_le_bytes());
+            region.extend_from_slice(b"rgn ");
+            // Country, language, dialect and code page.
+            region.extend_from_slice(&[0; 8]);
+            notes.push(Chunk::new(b"ltxt", region));
+        }
+    }
+
+    let mut chunks = vec![Chunk::new(b"cue ", points)];
+    if !notes.is_empty() {
+        chunks.push(Chunk::list(b"adtl", &notes));
+    }
+    chunks
+}
+
+/// The markers of a WAV file's `cue ` and `LIST adtl` chunks.
+pub fn parse_cues(chunks: &[Chunk]) -> Vec<CuePoint> {
+    let u32_at = |data: &[u8], at: usize| {
+        data.get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let mut labels = HashMap::new();
+    let mut lengths = HashMap::new();
+    for chunk in chunks {
+        if &chunk.id != b"LIST" || !chunk.data.starts_with(b"adtl") {
+            continue;
+        }
+        let mut offset = 4;
+        while let (Some(len), Some(id)) = (
+            u32_at(&chunk.data, offset + 4),
+            u32_at(&chunk.data, offset + 8),
+        ) {
+            let len = len as usize;
+            let Some(body) = chunk.data.get(offset + 12..offset + 8 + len) else {
+                break;
+            };
+            match &chunk.data[offset..offset + 4] {
+                b"labl" => {
+                    let end = body.iter().position(|&b| b == 0).unwrap_or(body.len());
+                    labels.insert(id, String::from_utf8_lossy(&body[..end]).into_owned());
+                }
+                b"ltxt" => {
+                    if let Some(length) = u32_at(body, 0) {
+                        lengths.insert(id, u64::from(length));
+                    }
+                }
+                _ => {}
+            }
+            offset += 8 + len + len % 2;
+        }
+    }
+
+    let Some(cue) = chunks.iter().find(|chunk| &chunk.id == b"cue ") else {
+        return Vec::new();
+    };
+    let count = u32_at(&cue.data, 0).unwrap_or(0) as usize;
+    (0..count)
+        .map_while(|n| {
+            let at = 4 + n * 24;
+            let id = u32_at(&cue.data, at)?;
+            let frame = u32_at(&cue.data, at + 20)?;
+            Some(CuePoint {
+                frame: u64::from(frame),
+                length: lengths.get(&id).copied(),
+                label: labels.get(&id).cloned().unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CuePoint, MarkerDetector, MarkerOptions, SilenceSplit, cue_chunks, parse_cues};
+
+    fn cue(frame: u64, length: Option<u64>, label: &str) -> CuePoint {
+        CuePoint {
+            frame,
+            length,
+            label: label.into(),
+        }
+    }
+
+    #[test]
+    fn writes_and_reads_back_cues() {
+        let cues = vec![
+            cue(0, None, "Start"),
+            cue(4_800, Some(9_600), "Chorus"),
+            cue(48_000, None, ""),
+        ];
+        let chunks = cue_chunks(&cues);
+
+        assert_eq!(&chunks[0].id, b"cue ");
+        assert_eq!(chunks[0].data.len(), 4 + 3 * 24);
+        assert_eq!(parse_cues(&chunks), cues);
+        assert!(cue_chunks(&[]).is_empty());
+    }
+
+    #[test]
+    fn finds_silence_splits_and_beats() {
+        let rate = 8_000;
+        let options = MarkerOptions {
+            silence: Some(SilenceSplit {
+                threshold_db: -50.0,
+                min_silence_ms: 500,
+            }),
+            ..MarkerOptions::default()
+        };
+        let mut detector = MarkerDetector::new(&options, rate, 1);
+        // One second of sound, one of silence, one of sound.
+        let tone = |n: usize| if n.is_multiple_of(2) { 0.5 } else { -0.5 };
+        let audio: Vec<f32> = (0..3 * 8_192)
+            .map(|n| {
+                if (8_192..16_384).contains(&n) {
+                    0.0
+                } else {
+                    tone(n)
+                }
+            })
+            .collect();
+        detector.process(&audio);
+        let cues = detector.finish(&[cue(100, None, "Input")], audio.len() as u64);
+        assert_eq!(
+            cues,
+            vec![cue(100, None, "Input"), cue(16_384, None, "Split 1")]
+        );
+
+        let options = MarkerOptions {
+            beats: true,
+            ..MarkerOptions::default()
+        };
+        let mut detector = MarkerDetector::new(&options, rate, 1);
+        // A click every half second over quiet noise.
+        let audio: Vec<f32> = (0..40 * 4_096)
+            .map(|n| if n % 4_096 < 1_024 { 0.8 } else { 0.01 })
+            .collect();
+        detector.process(&audio);
+        let beats = detector.finish(&[], audio.len() as u64);
+        assert!(beats.len() >= 20);
+        assert!(beats.iter().all(|beat| beat.frame % 4_096 == 0));
+    }
+
+    #[test]
+    fn rejects_invalid_markers() {
+        let options: MarkerOptions =
+            serde_json::from_str(r#"{"points": [{"position_seconds": -1.0}]}"#)
+                .expect("options should parse");
+
+        assert_eq!(options.validate().map_err(|e| e.code()), Err("invalid_job"));
+    }
+}
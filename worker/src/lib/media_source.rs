@@ -0,0 +1,718 @@
+use reqwest::StatusCode;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use symphonia::core::io::MediaSource;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::runtime::Handle;
+
+use crate::lib::limits::LimitExceeded;
+use crate::lib::storage::{S3Backend, S3Config, StorageBackend};
+use crate::lib::url_policy::UrlPolicy;
+
+/// Opens `file_path` as a streaming `MediaSource`.
+///
+/// Local paths are read straight from disk, but only from below the policy's
+/// `local_root`. Remote URLs and object storage URIs
+/// (`s3://bucket/key`, `r2://bucket/key`) are read lazily through ranged
+/// requests, so memory use stays bounded by the read-ahead buffer. Inputs
+/// larger than `max_bytes` are rejected before any decoding starts, and URLs
+/// and object URIs must pass the URL policy in `inputs`.
+pub async fn open_media_source(
+    file_path: &str,
+    max_bytes: u64,
+    inputs: &InputSources,
+) -> Result<Box<dyn MediaSource>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some((bucket, key)) = parse_object_uri(file_path) {
+        inputs.url_policy.check_object(bucket, key)?;
+        let Some(object_store) = &inputs.object_store else {
+            return Err(
+                format!("object storage is not configured, cannot read {file_path}").into(),
+            );
+        };
+        let storage = Arc::new(S3Backend::for_bucket(object_store, bucket));
+        return StorageSource::open(storage, key, inputs.read_ahead_bytes, max_bytes).await;
+    }
+
+    if is_remote_source(file_path) {
+        return HttpRangeSource::open(file_path, max_bytes, inputs).await;
+    }
+
+    let file = File::open(inputs.url_policy.check_path(Path::new(file_path))?)?;
+    check_size(file.metadata()?.len(), max_bytes)?;
+    Ok(Box::new(file))
+}
+
+/// Where `open_media_source` may read remote inputs from.
+#[derive(Clone)]
+pub struct InputSources {
+    pub url_policy: UrlPolicy,
+    /// Endpoint and credentials for `s3://` and `r2://` inputs; the bucket
+    /// comes from the URI.
+    pub object_store: Option<S3Config>,
+    /// Size of the ranges remote inputs are fetched in.
+    pub read_ahead_bytes: usize,
+    /// Where bodies of servers that ignore `Range` are spooled.
+    pub spool_dir: PathBuf,
+}
+
+pub fn is_remote_source(file_path: &str) -> bool {
+    file_path.starts_with("http://")
+        || file_path.starts_with("https://")
+        || parse_object_uri(file_path).is_some()
+}
+
+/// Splits `s3://bucket/key` or `r2://bucket/key` into bucket and key. Both
+/// schemes go through the S3 client configured for R2.
+pub fn parse_object_uri(uri: &str) -> Option<(&str, &str)> {
+    let rest = uri
+        .strip_prefix("s3://")
+        .or_else(|| uri.strip_prefix("r2://"))?;
+    let (bucket, key) = rest.split_once('/')?;
+    if bucket.is_empty() || key.is_empty() {
+        return None;
+    }
+    Some((bucket, key))
+}
+
+/// Fetches byte ranges of a remote object for `RangedSource`.
+trait RangeFetcher: Send + Sync {
+    /// Returns the bytes in `start..=end`.
+    fn fetch(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> impl Future<Output = Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>> + Send;
+}
+
+/// A seekable remote source that reads ahead in ranges of `read_ahead` bytes.
+///
+/// `read` blocks on the tokio runtime it was opened from, so it must only be
+/// driven from a blocking thread (e.g. inside `spawn_blocking`).
+struct RangedSource<F> {
+    fetcher: F,
+    handle: Handle,
+    len: u64,
+    pos: u64,
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    read_ahead: usize,
+}
+
+impl<F: RangeFetcher> RangedSource<F> {
+    /// `first_range` is the data already fetched while probing the object.
+    fn new(fetcher: F, len: u64, first_range: Vec<u8>, read_ahead: usize) -> Self {
+        Self {
+            fetcher,
+            handle: Handle::current(),
+            len,
+            pos: 0,
+            buffer: first_range,
+            buffer_start: 0,
+            read_ahead,
+        }
+    }
+
+    fn buffered(&self) -> Option<&[u8]> {
+        let end = self.buffer_start + self.buffer.len() as u64;
+        if self.pos < self.buffer_start || self.pos >= end {
+            return None;
+        }
+
+        let offset = (self.pos - self.buffer_start) as usize;
+        Some(&self.buffer[offset..])
+    }
+
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        let start = self.pos;
+        let end = (start + self.read_ahead as u64).min(self.len) - 1;
+
+        let bytes = self
+            .handle
+            .block_on(self.fetcher.fetch(start, end))
+            .map_err(io::Error::other)?;
+
+        // Not `UnexpectedEof`: symphonia takes that for the end of the stream
+        // and the job would succeed on a truncated input.
+        let expected = (end - start + 1) as usize;
+        if bytes.len() < expected {
+            return Err(io::Error::other(format!(
+                "range request for bytes {start}-{end} returned {} of {expected} bytes",
+                bytes.len()
+            )));
+        }
+
+        self.buffer = bytes;
+        self.buffer_start = start;
+        Ok(())
+    }
+}
+
+impl<F: RangeFetcher> Read for RangedSource<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+
+        if self.buffered().is_none() {
+            self.fill_buffer()?;
+        }
+
+        let available = self.buffered().unwrap_or_default();
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.pos += count as u64;
+        Ok(count)
+    }
+}
+
+impl<F: RangeFetcher> Seek for RangedSource<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        match target {
+            Some(target) => {
+                self.pos = target;
+                Ok(target)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl<F: RangeFetcher> MediaSource for RangedSource<F> {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
+
+/// A remote source read through HTTP Range requests.
+pub struct HttpRangeSource {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpRangeSource {
+    /// Probes the server with a ranged GET. Servers that ignore `Range` get their
+    /// body spooled to an unlinked file in the spool dir instead of being held
+    /// in memory.
+    pub async fn open(
+        url: &str,
+        max_bytes: u64,
+        inputs: &InputSources,
+    ) -> Result<Box<dyn MediaSource>, Box<dyn std::error::Error + Send + Sync>> {
+        let (url_policy, read_ahead) = (&inputs.url_policy, inputs.read_ahead_bytes);
+        let url = url_policy.parse_url(url)?;
+        let client = url_policy.client()?;
+        let response = client
+            .get(url)
+            .header(RANGE, format!("bytes=0-{}", read_ahead - 1))
+            .send()
+            .await?;
+
+        let response = response.error_for_status()?;
+        url_policy.check_content_type(&response)?;
+        let status = response.status();
+        // Later ranges go straight to where the redirects (if any) ended up.
+        let final_url = response.url().to_string();
+
+        let total_len = if status == StatusCode::PARTIAL_CONTENT {
+            response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_content_range_total)
+        } else {
+            None
+        };
+
+        let Some(len) = total_len else {
+            return Ok(Box::new(
+                spool_to_file(response, max_bytes, &inputs.spool_dir).await?,
+            ));
+        };
+        check_size(len, max_bytes)?;
+
+        let first_range = response.bytes().await?.to_vec();
+        let fetcher = Self {
+            client,
+            url: final_url,
+        };
+
+        Ok(Box::new(RangedSource::new(
+            fetcher,
+            len,
+            first_range,
+            read_ahead,
+        )))
+    }
+}
+
+impl RangeFetcher for HttpRangeSource {
+    async fn fetch(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .client
+            .get(&self.url)
+            .header(RANGE, format!("bytes={start}-{end}"))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(format!(
+                "range request returned {} instead of 206",
+                response.status()
+            )
+            .into());
+        }
+
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+/// An object read through a `StorageBackend`.
+pub struct StorageSource {
+    storage: Arc<dyn StorageBackend>,
+    key: String,
+}
+
+impl StorageSource {
+    pub async fn open(
+        storage: Arc<dyn StorageBackend>,
+        key: &str,
+        read_ahead: usize,
+        max_bytes: u64,
+    ) -> Result<Box<dyn MediaSource>, Box<dyn std::error::Error + Send + Sync>> {
+        let first_range = storage.get(key, 0..read_ahead as u64).await?;
+        check_size(first_range.total_len, max_bytes)?;
+
+        let fetcher = Self {
+            storage,
+            key: key.to_string(),
+        };
+
+        Ok(Box::new(RangedSource::new(
+            fetcher,
+            first_range.total_len,
+            first_range.data,
+            read_ahead,
+        )))
+    }
+}
+
+impl RangeFetcher for StorageSource {
+    async fn fetch(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.storage.get(&self.key, start..end + 1).await?.data)
+    }
+}
+
+/// Parses the total length out of a `Content-Range: bytes 0-99/1234` header.
+pub fn parse_content_range_total(value: &str) -> Option<u64> {
+    let (_, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    total.trim().parse().ok()
+}
+
+fn check_size(bytes: u64, max_bytes: u64) -> Result<(), LimitExceeded> {
+    if bytes > max_bytes {
+        return Err(LimitExceeded::InputSize {
+            bytes,
+            max: max_bytes,
+        });
+    }
+    Ok(())
+}
+
+async fn spool_to_file(
+    mut response: reqwest::Response,
+    max_bytes: u64,
+    spool_dir: &Path,
+) -> Result<File, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(len) = response.content_length() {
+        check_size(len, max_bytes)?;
+    }
+
+    let path = spool_path(spool_dir);
+    let mut file = tokio::fs::File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await?;
+    // The open handle keeps the data alive; unlinking now means nothing is left
+    // behind if the worker dies mid-job.
+    tokio::fs::remove_file(&path).await?;
+
+    // Content-Length may be missing or wrong, so count what actually arrives.
+    let mut received = 0u64;
+    while let Some(chunk) = response.chunk().await? {
+        received += chunk.len() as u64;
+        check_size(received, max_bytes)?;
+        file.write_all(&chunk).await?;
+    }
+
+    file.seek(SeekFrom::Start(0)).await?;
+    Ok(file.into_std().await)
+}
+
+fn spool_path(spool_dir: &Path) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+
+    spool_dir.join(format!("audio-spool-{}-{nanos}.bin", std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InputSources;
+    use super::{
+        HttpRangeSource, RangeFetcher, RangedSource, is_remote_source, open_media_source,
+        parse_content_range_total, parse_object_uri,
+    };
+    use crate::lib::limits::LimitExceeded;
+    use crate::lib::retry::find_cause;
+    use crate::lib::url_policy::{InputRejected, UrlPolicy};
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn unique_temp_file() -> std::path::PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should be monotonic")
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("audio-input-{nanos}.bin"))
+    }
+
+    /// Serves `body` over HTTP, honouring `Range` headers only when `ranges` is set.
+    async fn serve(body: Vec<u8>, ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener should bind");
+        let addr = listener
+            .local_addr()
+            .expect("listener should have an address");
+
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match socket.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&chunk[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).to_lowercase();
+                    let range = request
+                        .lines()
+                        .find_map(|line| line.strip_prefix("range: bytes="))
+                        .and_then(|range| range.trim().split_once('-'))
+                        .map(|(start, end)| {
+                            let start: usize = start.parse().unwrap();
+                            let end: usize = end.parse::<usize>().unwrap().min(body.len() - 1);
+                            (start, end)
+                        });
+
+                    let (head, payload) = match range.filter(|_| ranges) {
+                        Some((start, end)) => (
+                            format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{end}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                                body.len(),
+                                end - start + 1
+                            ),
+                            body[start..=end].to_vec(),
+                        ),
+                        None => (
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                                body.len()
+                            ),
+                            body,
+                        ),
+                    };
+                    let _ = socket.write_all(head.as_bytes()).await;
+                    let _ = socket.write_all(&payload).await;
+                });
+            }
+        });
+
+        format!("http://{addr}/audio.wav")
+    }
+
+    /// The test server listens on loopback, which the real policy blocks.
+    fn local_policy() -> UrlPolicy {
+        UrlPolicy {
+            allowlist: Vec::new(),
+            object_allowlist: Vec::new(),
+            local_root: std::env::temp_dir(),
+            allow_private_networks: true,
+            max_redirects: 3,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn local_inputs() -> InputSources {
+        InputSources {
+            url_policy: local_policy(),
+            object_store: None,
+            read_ahead_bytes: 1024,
+            spool_dir: std::env::temp_dir(),
+        }
+    }
+
+    fn sample_body() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn detects_remote_sources() {
+        assert!(is_remote_source("https://example.com/audio.mp3"));
+        assert!(is_remote_source("http://example.com/audio.mp3"));
+        assert!(is_remote_source("r2://uploads/audio.mp3"));
+        assert!(!is_remote_source("/app/data/inputs/test.wav"));
+    }
+
+    #[test]
+    fn parses_object_storage_uris() {
+        assert_eq!(
+            parse_object_uri("s3://uploads/users/1/take.flac"),
+            Some(("uploads", "users/1/take.flac"))
+        );
+        assert_eq!(
+            parse_object_uri("r2://uploads/take.wav"),
+            Some(("uploads", "take.wav"))
+        );
+        assert_eq!(parse_object_uri("s3://uploads/"), None);
+        assert_eq!(parse_object_uri("s3://uploads"), None);
+        assert_eq!(parse_object_uri("https://uploads/take.wav"), None);
+    }
+
+    #[test]
+    fn parses_content_range_total() {
+        assert_eq!(parse_content_range_total("bytes 0-99/1234"), Some(1234));
+        assert_eq!(parse_content_range_total("bytes 0-99/*"), None);
+    }
+
+    #[tokio::test]
+    async fn opens_local_audio_source() {
+        let temp_file = unique_temp_file();
+        fs::write(&temp_file, b"local-audio").expect("temp file should be written");
+
+        let mut source = open_media_source(
+            temp_file.to_str().expect("temp path should be valid UTF-8"),
+            1024,
+            &local_inputs(),
+        )
+        .await
+        .expect("local source should open");
+
+        let mut bytes = Vec::new();
+        source
+            .read_to_end(&mut bytes)
+            .expect("local source should be readable");
+        assert_eq!(bytes, b"local-audio");
+
+        let _ = fs::remove_file(temp_file);
+    }
+
+    #[tokio::test]
+    async fn refuses_local_paths_outside_the_input_root() {
+        let root = unique_temp_file().with_extension("inputs");
+        fs::create_dir_all(&root).expect("input root should be created");
+        let outside = unique_temp_file();
+        fs::write(&outside, b"secret").expect("temp file should be written");
+        let inputs = InputSources {
+            url_policy: UrlPolicy {
+                local_root: root.clone(),
+                ..local_policy()
+            },
+            ..local_inputs()
+        };
+
+        let escape = root
+            .join("..")
+            .join(outside.file_name().expect("file name"));
+        for path in [
+            "/etc/passwd".to_string(),
+            escape.display().to_string(),
+            root.join("missing.wav").display().to_string(),
+        ] {
+            let error = match open_media_source(&path, 1024, &inputs).await {
+                Ok(_) => panic!("{path} should be refused"),
+                Err(error) => error,
+            };
+            assert!(
+                find_cause::<InputRejected>(error.as_ref()).is_some(),
+                "{path}"
+            );
+        }
+
+        let _ = fs::remove_file(outside);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn refuses_objects_outside_the_allowlist() {
+        let inputs = InputSources {
+            url_policy: UrlPolicy {
+                object_allowlist: vec!["uploads/inputs/".into()],
+                ..local_policy()
+            },
+            ..local_inputs()
+        };
+
+        for uri in [
+            "s3://outputs/cache/abc.wav",
+            "r2://uploads/.jobs/job-1.done",
+        ] {
+            let error = match open_media_source(uri, 1024, &inputs).await {
+                Ok(_) => panic!("{uri} should be refused"),
+                Err(error) => error,
+            };
+            assert!(
+                find_cause::<InputRejected>(error.as_ref()).is_some(),
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_remote_source_in_ranges() {
+        let body = sample_body();
+        let url = serve(body.clone(), true).await;
+
+        let mut source = HttpRangeSource::open(&url, u64::MAX, &local_inputs())
+            .await
+            .expect("remote source should open");
+        assert_eq!(source.byte_len(), Some(body.len() as u64));
+
+        let (head, tail) = tokio::task::spawn_blocking(move || {
+            let mut all = Vec::new();
+            source
+                .read_to_end(&mut all)
+                .expect("source should be readable");
+
+            source
+                .seek(SeekFrom::Start(4_000))
+                .expect("source should seek");
+            let mut tail = vec![0u8; 16];
+            source
+                .read_exact(&mut tail)
+                .expect("seeked read should work");
+            (all, tail)
+        })
+        .await
+        .expect("blocking read should finish");
+
+        assert_eq!(head, body);
+        assert_eq!(tail, body[4_000..4_016]);
+    }
+
+    /// Serves ranges of `body` but nothing past `cutoff`, like an object
+    /// that was truncated after it was opened.
+    struct ShortFetcher {
+        body: Vec<u8>,
+        cutoff: usize,
+    }
+
+    impl RangeFetcher for ShortFetcher {
+        async fn fetch(
+            &self,
+            start: u64,
+            end: u64,
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            let end = (end as usize + 1).min(self.cutoff);
+            Ok(self
+                .body
+                .get(start as usize..end)
+                .unwrap_or_default()
+                .to_vec())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fails_when_a_range_stops_short() {
+        let body = sample_body();
+
+        for cutoff in [1_024, 1_500] {
+            let fetcher = ShortFetcher {
+                body: body.clone(),
+                cutoff,
+            };
+            let first_range = body[..1_024].to_vec();
+            let mut source = RangedSource::new(fetcher, body.len() as u64, first_range, 1_024);
+
+            let error = tokio::task::spawn_blocking(move || {
+                source
+                    .read_to_end(&mut Vec::new())
+                    .expect_err("truncated source should fail")
+            })
+            .await
+            .expect("blocking read should finish");
+            assert_ne!(error.kind(), std::io::ErrorKind::UnexpectedEof, "{cutoff}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spools_remote_source_without_range_support() {
+        let body = sample_body();
+        let url = serve(body.clone(), false).await;
+
+        let mut source = HttpRangeSource::open(&url, u64::MAX, &local_inputs())
+            .await
+            .expect("remote source should open");
+
+        let mut bytes = Vec::new();
+        source
+            .read_to_end(&mut bytes)
+            .expect("spooled source should be readable");
+        assert_eq!(bytes, body);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_inputs_over_the_size_limit() {
+        let body = sample_body();
+
+        for ranges in [true, false] {
+            let url = serve(body.clone(), ranges).await;
+            let error = match HttpRangeSource::open(&url, 5_000, &local_inputs()).await {
+                Ok(_) => panic!("oversized input should be rejected"),
+                Err(error) => error,
+            };
+            assert!(error.downcast_ref::<LimitExceeded>().is_some());
+        }
+    }
+}
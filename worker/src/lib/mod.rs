@@ -1,4 +1,26 @@
+pub mod atomic_file;
 pub mod audio_processor;
-pub mod cloudflare;
+pub mod broadcast;
+pub mod broker;
+pub mod cache;
+pub mod cancellation;
+pub mod config;
+pub mod control;
 pub mod effects;
+pub mod error;
+pub mod idempotency;
+pub mod job_handler;
+pub mod limits;
+pub mod markers;
+pub mod media_source;
+pub mod retention;
+pub mod retry;
+pub mod riff;
+pub mod shutdown;
+pub mod spec_conversion;
+pub mod status;
 pub mod storage;
+pub mod supervisor;
+pub mod tags;
+pub mod tracks;
+pub mod url_policy;
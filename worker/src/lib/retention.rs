@@ -0,0 +1,344 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::lib::atomic_file::PARTIAL_EXTENSION;
+use crate::lib::cache::CACHE_PREFIX;
+use crate::lib::idempotency::MARKER_DIR;
+
+/// Directories under the storage root the worker shares with the API and
+/// must never sweep: uploaded inputs, some still waiting in the queue, and
+/// the idempotency markers.
+const PROTECTED_DIRS: &[&str] = &["inputs", MARKER_DIR];
+
+/// What the sweeper keeps under the local storage root and the scratch root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Directories under the storage root the worker writes outputs to, e.g.
+    /// `processed`. Nothing outside them and the output cache is swept.
+    pub output_dirs: Vec<String>,
+    /// Outputs not modified for this long are deleted. `None` keeps them
+    /// forever.
+    pub output_ttl: Option<Duration>,
+    /// Once outputs and cache entries grow past this, the least recently used
+    /// ones are deleted until they fit again, cache entries first.
+    pub quota_bytes: Option<u64>,
+    pub sweep_interval: Duration,
+    /// Scratch files older than this belong to a replica that died mid-job.
+    pub scratch_ttl: Duration,
+}
+
+/// Where a replica writes outputs while it is still producing them. Each
+/// replica gets its own directory, so cleaning up after a restart never
+/// touches files other replicas on the same volume are writing.
+pub fn replica_scratch_dir(scratch_root: &Path) -> PathBuf {
+    let replica = std::env::var("WORKER_REPLICA_ID")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| format!("pid-{}", std::process::id()));
+    let safe: String = replica
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    scratch_root.join(safe)
+}
+
+/// Empties this replica's scratch directory. Anything in it was left behind by
+/// a previous run of the same replica, since jobs do not survive a restart.
+pub fn reset_scratch_dir(scratch_dir: &Path) -> io::Result<()> {
+    if scratch_dir.exists() {
+        println!("Cleaning up scratch files in {}", scratch_dir.display());
+        fs::remove_dir_all(scratch_dir)?;
+    }
+    fs::create_dir_all(scratch_dir)
+}
+
+/// Sweeps the output directories and cache under `storage_root`, and
+/// `scratch_root`, every `sweep_interval`.
+pub async fn run_sweeper(policy: RetentionPolicy, storage_root: PathBuf, scratch_root: PathBuf) {
+    let mut interval = tokio::time::interval(policy.sweep_interval);
+    loop {
+        interval.tick().await;
+
+        let policy = policy.clone();
+        let storage_root = storage_root.clone();
+        let scratch_root = scratch_root.clone();
+        let swept =
+            tokio::task::spawn_blocking(move || sweep(&policy, &storage_root, &scratch_root)).await;
+
+        match swept {
+            Ok(Ok(0)) => {}
+            Ok(Ok(removed)) => println!("Retention sweep removed {} files", removed),
+            Ok(Err(e)) => eprintln!("Retention sweep failed: {}", e),
+            Err(e) => eprintln!("Retention sweep panicked: {}", e),
+        }
+    }
+}
+
+/// One sweep: expire old outputs, then evict outputs and cache entries until
+/// the quota holds. Returns the number of files removed.
+pub fn sweep(
+    policy: &RetentionPolicy,
+    storage_root: &Path,
+    scratch_root: &Path,
+) -> io::Result<usize> {
+    let now = SystemTime::now();
+    let mut removed = 0;
+
+    let mut scratch = Vec::new();
+    collect_files(scratch_root, &[], &mut scratch)?;
+    for file in scratch {
+        if file.age(now) > policy.scratch_ttl && remove(&file.path) {
+            removed += 1;
+        }
+    }
+
+    let mut skip: Vec<PathBuf> = PROTECTED_DIRS
+        .iter()
+        .map(|dir| storage_root.join(dir))
+        .collect();
+    skip.push(scratch_root.to_path_buf());
+    let mut outputs = Vec::new();
+    for dir in &policy.output_dirs {
+        let dir = storage_root.join(dir);
+        // `inputs`, `.jobs` or the root itself would sweep what we must keep.
+        if skip.iter().any(|protected| protected.starts_with(&dir)) {
+            eprintln!(
+                "Not sweeping {}: it holds inputs or job markers",
+                dir.display()
+            );
+            continue;
+        }
+        collect_files(&dir, &skip, &mut outputs)?;
+    }
+    let mut cached = Vec::new();
+    collect_files(&storage_root.join(CACHE_PREFIX), &skip, &mut cached)?;
+
+    // Partial files left by a crashed copy; live ones are far younger.
+    let mut remove_orphans = |files: &mut Vec<FileEntry>| {
+        files.retain(|file| {
+            let orphaned =
+                file.is_partial() && file.age(now) > policy.scratch_ttl && remove(&file.path);
+            removed += usize::from(orphaned);
+            !orphaned
+        });
+    };
+    remove_orphans(&mut outputs);
+    remove_orphans(&mut cached);
+    if let Some(ttl) = policy.output_ttl {
+        outputs.retain(|file| {
+            let expired = file.age(now) > ttl && remove(&file.path);
+            removed += usize::from(expired);
+            !expired
+        });
+    }
+
+    if let Some(quota) = policy.quota_bytes {
+        let mut total: u64 = outputs.iter().chain(&cached).map(|file| file.size).sum();
+        // Cache entries can be rebuilt, outputs cannot.
+        cached.sort_by_key(|file| file.last_used);
+        outputs.sort_by_key(|file| file.last_used);
+        for file in cached.iter().chain(&outputs) {
+            if total <= quota {
+                break;
+            }
+            if remove(&file.path) {
+                total = total.saturating_sub(file.size);
+                removed += 1;
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+struct FileEntry {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+    /// Access time where the filesystem records it, otherwise `modified`.
+    last_used: SystemTime,
+}
+
+impl FileEntry {
+    fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.modified).unwrap_or_default()
+    }
+
+    fn is_partial(&self) -> bool {
+        self.path
+            .extension()
+            .is_some_and(|extension| extension == PARTIAL_EXTENSION)
+    }
+}
+
+fn collect_files(dir: &Path, skip: &[PathBuf], files: &mut Vec<FileEntry>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        if skip.contains(&path) {
+            continue;
+        }
+
+        // Files can disappear while we walk, e.g. a job finishing its upload.
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            collect_files(&path, skip, files)?;
+        } else if metadata.is_file() {
+            let modified = metadata.modified()?;
+            files.push(FileEntry {
+                path,
+                size: metadata.len(),
+                modified,
+                last_used: metadata.accessed().unwrap_or(modified).max(modified),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn remove(path: &Path) -> bool {
+    match fs::remove_file(path) {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => {
+            eprintln!("Failed to remove {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetentionPolicy, sweep};
+    use std::fs::{self, File};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn unique_temp_dir(name: &str) -> std::path::PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should be monotonic")
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("worker-retention-{name}-{nanos}"))
+    }
+
+    fn write_aged(path: &Path, len: usize, age: Duration) {
+        fs::create_dir_all(path.parent().expect("file should have a parent"))
+            .expect("dir should be created");
+        fs::write(path, vec![0u8; len]).expect("file should be written");
+        let time = SystemTime::now() - age;
+        File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| {
+                file.set_times(fs::FileTimes::new().set_accessed(time).set_modified(time))
+            })
+            .expect("times should be set");
+    }
+
+    fn policy() -> RetentionPolicy {
+        RetentionPolicy {
+            output_dirs: vec!["processed".into()],
+            output_ttl: Some(Duration::from_secs(3600)),
+            quota_bytes: None,
+            sweep_interval: Duration::from_secs(60),
+            scratch_ttl: Duration::from_secs(600),
+        }
+    }
+
+    #[test]
+    fn expires_old_outputs_but_keeps_fresh_files() {
+        let root = unique_temp_dir("ttl");
+        let scratch = root.join(".scratch");
+        let hour = Duration::from_secs(3600);
+        write_aged(&root.join("processed/old.wav"), 4, 2 * hour);
+        write_aged(&root.join("processed/new.wav"), 4, Duration::ZERO);
+        write_aged(&root.join("processed/b.wav.1-2-3.partial"), 4, 2 * hour);
+        write_aged(&scratch.join("a/partial.wav"), 4, 2 * hour);
+        write_aged(&scratch.join("b/writing.wav"), 4, Duration::ZERO);
+
+        let removed = sweep(&policy(), &root, &scratch).expect("sweep should succeed");
+
+        assert_eq!(removed, 3);
+        assert!(!root.join("processed/old.wav").exists());
+        assert!(root.join("processed/new.wav").exists());
+        assert!(!scratch.join("a/partial.wav").exists());
+        assert!(scratch.join("b/writing.wav").exists());
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn never_touches_inputs_or_job_markers() {
+        let root = unique_temp_dir("protected");
+        let hour = Duration::from_secs(3600);
+        let kept = [
+            "inputs/queued.wav",
+            "inputs/nested/queued.wav",
+            ".jobs/job-1.lock",
+            ".jobs/job-2.done",
+            "other/unrelated.bin",
+        ];
+        for path in kept {
+            write_aged(&root.join(path), 10, 2 * hour);
+        }
+        write_aged(&root.join("processed/old.wav"), 10, 2 * hour);
+        let policy = RetentionPolicy {
+            output_dirs: vec!["processed".into(), "inputs".into(), String::new()],
+            quota_bytes: Some(0),
+            ..policy()
+        };
+
+        let removed = sweep(&policy, &root, &root.join(".scratch")).expect("sweep should succeed");
+
+        assert_eq!(removed, 1);
+        for path in kept {
+            assert!(root.join(path).exists(), "{path}");
+        }
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn evicts_cache_entries_then_least_recently_used_outputs_over_quota() {
+        let root = unique_temp_dir("quota");
+        let minute = Duration::from_secs(60);
+        write_aged(&root.join("processed/oldest.wav"), 10, 3 * minute);
+        write_aged(&root.join("processed/older.wav"), 10, 2 * minute);
+        write_aged(&root.join("processed/newest.wav"), 10, minute);
+        write_aged(&root.join("cache/fresh.wav"), 10, Duration::ZERO);
+        let policy = RetentionPolicy {
+            output_ttl: None,
+            quota_bytes: Some(20),
+            ..policy()
+        };
+
+        let removed = sweep(&policy, &root, &root.join(".scratch")).expect("sweep should succeed");
+
+        assert_eq!(removed, 2);
+        assert!(!root.join("cache/fresh.wav").exists());
+        assert!(!root.join("processed/oldest.wav").exists());
+        assert!(root.join("processed/older.wav").exists());
+        assert!(root.join("processed/newest.wav").exists());
+
+        let _ = fs::remove_dir_all(root);
+    }
+}
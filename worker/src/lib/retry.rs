@@ -0,0 +1,325 @@
+use lapin::BasicProperties;
+use lapin::types::{AMQPValue, FieldTable};
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+
+use crate::lib::error::WorkerError;
+use crate::lib::limits::LimitExceeded;
+use crate::lib::url_policy::InputRejected;
+
+pub const RETRY_COUNT_HEADER: &str = "x-retry-count";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// Worth retrying later: network hiccups, storage outages, timeouts.
+    Transient,
+    /// Retrying cannot help: corrupt input, unsupported codec, bad job payload.
+    Permanent,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RetryDecision {
+    Retry { attempt: u32, queue: String },
+    DeadLetter,
+}
+
+/// Delayed retries go through one TTL queue per job queue and attempt, each
+/// dead-lettering back into the job queue it came from once its messages expire.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn decide(&self, job_queue: &str, kind: FailureKind, retries_so_far: u32) -> RetryDecision {
+        if kind == FailureKind::Permanent || retries_so_far >= self.max_retries {
+            return RetryDecision::DeadLetter;
+        }
+
+        let attempt = retries_so_far + 1;
+        RetryDecision::Retry {
+            attempt,
+            queue: self.retry_queue(job_queue, attempt),
+        }
+    }
+
+    /// Exponential backoff: `base_delay * 2^(attempt - 1)`.
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor)
+    }
+
+    /// The delay is part of the name so changing the policy declares fresh
+    /// queues instead of clashing with the `x-message-ttl` of existing ones.
+    pub fn retry_queue(&self, job_queue: &str, attempt: u32) -> String {
+        format!(
+            "{}.retry.{}ms",
+            job_queue,
+            self.delay_for(attempt).as_millis()
+        )
+    }
+
+    pub fn retry_queues(&self, job_queue: &str) -> Vec<(String, Duration)> {
+        (1..=self.max_retries)
+            .map(|attempt| {
+                (
+                    self.retry_queue(job_queue, attempt),
+                    self.delay_for(attempt),
+                )
+            })
+            .collect()
+    }
+}
+
+pub fn retry_count(properties: &BasicProperties) -> u32 {
+    let value = properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(RETRY_COUNT_HEADER));
+
+    match value {
+        Some(AMQPValue::ShortShortUInt(v)) => u32::from(*v),
+        Some(AMQPValue::ShortUInt(v)) => u32::from(*v),
+        Some(AMQPValue::LongUInt(v)) => *v,
+        Some(AMQPValue::ShortShortInt(v)) => u32::try_from(*v).unwrap_or(0),
+        Some(AMQPValue::ShortInt(v)) => u32::try_from(*v).unwrap_or(0),
+        Some(AMQPValue::LongInt(v)) => u32::try_from(*v).unwrap_or(0),
+        Some(AMQPValue::LongLongInt(v)) => u32::try_from(*v).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Copies the original headers and stamps them with `retry_count` plus any
+/// extra string headers (e.g. the failure reason for the dead-letter queue).
+pub fn republish_properties(
+    properties: &BasicProperties,
+    retry_count: u32,
+    extra: &[(&str, &str)],
+) -> BasicProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    headers.insert(RETRY_COUNT_HEADER.into(), AMQPValue::LongUInt(retry_count));
+    for (key, value) in extra {
+        headers.insert((*key).into(), AMQPValue::LongString((*value).into()));
+    }
+
+    let republished = BasicProperties::default()
+        .with_content_type("application/json".into())
+        .with_delivery_mode(2)
+        .with_headers(headers);
+
+    // Keep the job's place in line when it comes back to a priority queue.
+    match properties.priority() {
+        Some(priority) => republished.with_priority(*priority),
+        None => republished,
+    }
+}
+
+pub fn retry_queue_arguments(job_queue: &str, ttl: Duration) -> FieldTable {
+    let mut arguments = FieldTable::default();
+    arguments.insert(
+        "x-message-ttl".into(),
+        AMQPValue::LongLongInt(ttl.as_millis().min(i64::MAX as u128) as i64),
+    );
+    arguments.insert(
+        "x-dead-letter-exchange".into(),
+        AMQPValue::LongString("".into()),
+    );
+    arguments.insert(
+        "x-dead-letter-routing-key".into(),
+        AMQPValue::LongString(job_queue.into()),
+    );
+    arguments
+}
+
+/// Walks the error chain looking for a cause we know how to classify. Anything
+/// unrecognised is treated as permanent so it cannot loop forever.
+pub fn classify(error: &(dyn Error + 'static)) -> FailureKind {
+    // Checked first because they usually arrive wrapped in errors (e.g. from
+    // reqwest) that would otherwise be classified as transient.
+    if find_cause::<LimitExceeded>(error).is_some() || find_cause::<InputRejected>(error).is_some()
+    {
+        return FailureKind::Permanent;
+    }
+    if let Some(error) = find_cause::<WorkerError>(error) {
+        return if error.retryable() {
+            FailureKind::Transient
+        } else {
+            FailureKind::Permanent
+        };
+    }
+
+    let mut current = Some(error);
+
+    while let Some(error) = current {
+        if let Some(kind) = classify_known(error) {
+            return kind;
+        }
+        current = error.source();
+    }
+
+    FailureKind::Permanent
+}
+
+/// Finds an error of type `T` anywhere in `error`'s source chain.
+pub fn find_cause<'a, T: Error + 'static>(error: &'a (dyn Error + 'static)) -> Option<&'a T> {
+    let mut current = Some(error);
+
+    while let Some(error) = current {
+        if let Some(cause) = error.downcast_ref::<T>() {
+            return Some(cause);
+        }
+        // `io::Error::source` skips the error it wraps, so look inside first.
+        current = match error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+        {
+            Some(inner) => Some(inner as &(dyn Error + 'static)),
+            None => error.source(),
+        };
+    }
+
+    None
+}
+
+fn classify_known(error: &(dyn Error + 'static)) -> Option<FailureKind> {
+    if let Some(error) = error.downcast_ref::<io::Error>() {
+        return Some(classify_io(error));
+    }
+
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        if let Some(status) = error.status() {
+            let retryable = status.is_server_error()
+                || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                || status == reqwest::StatusCode::REQUEST_TIMEOUT;
+            return Some(if retryable {
+                FailureKind::Transient
+            } else {
+                FailureKind::Permanent
+            });
+        }
+        if error.is_builder() {
+            return Some(FailureKind::Permanent);
+        }
+        return Some(FailureKind::Transient);
+    }
+
+    if let Some(error) = error.downcast_ref::<symphonia::core::errors::Error>() {
+        return Some(match error {
+            symphonia::core::errors::Error::IoError(error) => classify_io(error),
+            _ => FailureKind::Permanent,
+        });
+    }
+
+    if let Some(error) = error.downcast_ref::<hound::Error>() {
+        return Some(match error {
+            hound::Error::IoError(error) => classify_io(error),
+            _ => FailureKind::Permanent,
+        });
+    }
+
+    None
+}
+
+fn classify_io(error: &io::Error) -> FailureKind {
+    match error.kind() {
+        io::ErrorKind::NotFound
+        | io::ErrorKind::PermissionDenied
+        | io::ErrorKind::InvalidInput
+        | io::ErrorKind::InvalidData
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::Unsupported => FailureKind::Permanent,
+        _ => FailureKind::Transient,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FailureKind, RetryDecision, RetryPolicy, classify, find_cause, republish_properties,
+        retry_count,
+    };
+    use crate::lib::limits::LimitExceeded;
+    use lapin::BasicProperties;
+    use std::io;
+    use std::time::Duration;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn retries_transient_failures_with_exponential_delay() {
+        let policy = policy();
+
+        assert_eq!(
+            policy.decide("audio_jobs", FailureKind::Transient, 0),
+            RetryDecision::Retry {
+                attempt: 1,
+                queue: "audio_jobs.retry.5000ms".into()
+            }
+        );
+        assert_eq!(policy.delay_for(3), Duration::from_secs(20));
+        assert_eq!(policy.retry_queues("audio_jobs").len(), 3);
+        assert_eq!(
+            policy.retry_queue("audio_jobs.preview", 2),
+            "audio_jobs.preview.retry.10000ms"
+        );
+    }
+
+    #[test]
+    fn dead_letters_permanent_or_exhausted_failures() {
+        let policy = policy();
+
+        assert_eq!(
+            policy.decide("audio_jobs", FailureKind::Permanent, 0),
+            RetryDecision::DeadLetter
+        );
+        assert_eq!(
+            policy.decide("audio_jobs", FailureKind::Transient, 3),
+            RetryDecision::DeadLetter
+        );
+    }
+
+    #[test]
+    fn carries_retry_count_in_headers() {
+        let original = BasicProperties::default().with_priority(7);
+        assert_eq!(retry_count(&original), 0);
+
+        let republished = republish_properties(&original, 2, &[("x-error", "boom")]);
+        assert_eq!(retry_count(&republished), 2);
+        assert_eq!(*republished.priority(), Some(7));
+    }
+
+    #[test]
+    fn classifies_errors_through_the_source_chain() {
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "slow");
+        assert_eq!(classify(&timeout), FailureKind::Transient);
+
+        let missing = symphonia::core::errors::Error::IoError(io::Error::new(
+            io::ErrorKind::NotFound,
+            "gone",
+        ));
+        assert_eq!(classify(&missing), FailureKind::Permanent);
+
+        let corrupt = symphonia::core::errors::Error::DecodeError("bad frame");
+        assert_eq!(classify(&corrupt), FailureKind::Permanent);
+
+        let unknown: Box<dyn std::error::Error + Send + Sync> = "something odd".into();
+        assert_eq!(classify(unknown.as_ref()), FailureKind::Permanent);
+    }
+
+    #[test]
+    fn finds_causes_wrapped_in_io_errors() {
+        let limit = LimitExceeded::Timeout(Duration::from_secs(5));
+        let wrapped = io::Error::other(limit.clone());
+
+        assert_eq!(find_cause::<LimitExceeded>(&wrapped), Some(&limit));
+        assert_eq!(classify(&wrapped), FailureKind::Permanent);
+        assert_eq!(find_cause::<LimitExceeded>(&io::Error::other("boom")), None);
+    }
+}
@@ -0,0 +1,257 @@
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use symphonia::core::io::MediaSource;
+
+/// How much of the start of a source is kept. Chunks in front of the
+/// samples usually fit; the rest are read by [`read_wav_chunks`].
+const HEAD_CAPTURE_BYTES: usize = 256 * 1024;
+
+/// Larger chunks found after the head, e.g. the samples, are skipped.
+const MAX_CHUNK_READ_BYTES: u32 = 256 * 1024;
+
+/// A RIFF chunk: a four-character id and its payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn new(id: &[u8; 4], data: Vec<u8>) -> Self {
+        Self { id: *id, data }
+    }
+
+    /// A `LIST` chunk of the given type holding `chunks`, e.g. `INFO`.
+    pub fn list(kind: &[u8; 4], chunks: &[Chunk]) -> Self {
+        let mut data = kind.to_vec();
+        for chunk in chunks {
+            chunk.write_to(&mut data);
+        }
+        Self::new(b"LIST", data)
+    }
+
+    /// Header, payload and the pad byte that keeps the next chunk word-aligned.
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.id);
+        out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.data);
+        if self.data.len() % 2 == 1 {
+            out.push(0);
+        }
+    }
+}
+
+/// Appends `chunks` to the finalized WAV file at `path` and updates the RIFF
+/// size. `hound` only writes `fmt ` and `data`, so everything else is added
+/// after the samples, where readers look for it too.
+pub fn append_chunks(path: &Path, chunks: &[Chunk]) -> io::Result<()> {
+    if chunks.is_empty() {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a WAV file", path.display()),
+        ));
+    }
+
+    let mut bytes = Vec::new();
+    for chunk in chunks {
+        chunk.write_to(&mut bytes);
+    }
+    let end = file.seek(SeekFrom::End(0))?;
+    let riff_size = u32::try_from(end - 8 + bytes.len() as u64).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} would exceed the 4 GiB WAV limit", path.display()),
+        )
+    })?;
+    file.write_all(&bytes)?;
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&riff_size.to_le_bytes())?;
+    file.flush()
+}
+
+/// The chunks of the WAV file at `path`, in file order.
+#[cfg(test)]
+pub fn read_chunks(path: &Path) -> io::Result<Vec<Chunk>> {
+    Ok(parse_head(&std::fs::read(path)?).0)
+}
+
+/// The complete chunks at the start of a WAV file, in file order, and the
+/// offset of the first one cut off by the end of `bytes`. Anything but WAV
+/// has neither.
+fn parse_head(bytes: &[u8]) -> (Vec<Chunk>, Option<u64>) {
+    let mut chunks = Vec::new();
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return (chunks, None);
+    }
+
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let id = [
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ];
+        let len = u32::from_le_bytes([
+            bytes[offset + 4],
+            bytes[offset + 5],
+            bytes[offset + 6],
+            bytes[offset + 7],
+        ]) as usize;
+        let start = offset + 8;
+        let Some(data) = bytes.get(start..start + len) else {
+            break;
+        };
+        chunks.push(Chunk::new(&id, data.to_vec()));
+        offset = start + len + len % 2;
+    }
+    (chunks, Some(offset as u64))
+}
+
+/// The chunks of a WAV file whose first bytes are `head`, in file order.
+/// Those `head` does not hold in full, such as the ones many tools append
+/// after the samples, are read from `source` if it can seek. The samples
+/// are skipped.
+pub fn read_wav_chunks<S: MediaSource>(head: &[u8], source: &mut S) -> io::Result<Vec<Chunk>> {
+    let (mut chunks, Some(mut offset)) = parse_head(head) else {
+        return Ok(Vec::new());
+    };
+    let whole_file = offset >= head.len() as u64 && head.len() < HEAD_CAPTURE_BYTES;
+    if whole_file || !source.is_seekable() {
+        return Ok(chunks);
+    }
+
+    loop {
+        source.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        match source.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let id = [header[0], header[1], header[2], header[3]];
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if len <= MAX_CHUNK_READ_BYTES {
+            let mut data = vec![0u8; len as usize];
+            match source.read_exact(&mut data) {
+                Ok(()) => chunks.push(Chunk::new(&id, data)),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        offset += 8 + u64::from(len) + u64::from(len % 2);
+    }
+    Ok(chunks)
+}
+
+/// Passes a source through and keeps a copy of its first bytes, so chunks
+/// symphonia skips can be read once decoding is done.
+pub struct HeadCapture {
+    inner: Box<dyn MediaSource>,
+    head: Arc<Mutex<Vec<u8>>>,
+    position: u64,
+}
+
+impl HeadCapture {
+    /// The wrapped source and a handle to the bytes it captures.
+    pub fn new(inner: Box<dyn MediaSource>) -> (Self, Arc<Mutex<Vec<u8>>>) {
+        let head = Arc::new(Mutex::new(Vec::new()));
+        let capture = Self {
+            inner,
+            head: head.clone(),
+            position: 0,
+        };
+        (capture, head)
+    }
+}
+
+impl Read for HeadCapture {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let mut head = self
+            .head
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Only extends the copy while reading sequentially from its end.
+        if self.position == head.len() as u64 && head.len() < HEAD_CAPTURE_BYTES {
+            let keep = read.min(HEAD_CAPTURE_BYTES - head.len());
+            head.extend_from_slice(&buf[..keep]);
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for HeadCapture {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+impl MediaSource for HeadCapture {
+    fn is_seekable(&self) -> bool {
+        self.inner.is_seekable()
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.inner.byte_len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunk, append_chunks, read_chunks};
+    use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn unique_temp_file(name: &str) -> std::path::PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should be monotonic")
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("worker-riff-{name}-{nanos}.wav"))
+    }
+
+    #[test]
+    fn appends_chunks_readers_still_accept() {
+        let path = unique_temp_file("append");
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).expect("wav should be created");
+        for n in 0..100i16 {
+            writer.write_sample(n).expect("sample should be written");
+        }
+        writer.finalize().expect("wav should be finalized");
+
+        let info = Chunk::list(b"INFO", &[Chunk::new(b"INAM", b"Odd\0".to_vec())]);
+        let odd = Chunk::new(b"note", b"abc".to_vec());
+        append_chunks(&path, &[info.clone(), odd.clone()]).expect("chunks should be appended");
+
+        let bytes = std::fs::read(&path).expect("wav should be read");
+        let riff_size = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        assert_eq!(riff_size as usize, bytes.len() - 8);
+        assert_eq!(bytes.len() % 2, 0);
+
+        let chunks = read_chunks(&path).expect("chunks should be read");
+        assert_eq!(&chunks[chunks.len() - 2..], &[info, odd]);
+        let reader = WavReader::open(&path).expect("wav should still open");
+        assert_eq!(reader.len(), 100);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::lib::cancellation::{CancelReason, JobRegistry};
+
+/// How long cancelled jobs get to reach a packet boundary and requeue
+/// themselves before their tasks are aborted outright.
+const CANCELLED_JOB_WAIT: Duration = Duration::from_secs(5);
+
+/// Resolves once the process receives SIGTERM or SIGINT.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => eprintln!("Failed to install SIGTERM handler: {}", e),
+        }
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        eprintln!("Failed to listen for CTRL+C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Waits for in-flight jobs to finish within `grace_period`. Jobs still running
+/// after that are cancelled, which makes them clean up their partial output and
+/// nack their delivery with requeue.
+pub async fn drain_jobs(
+    in_flight: &mut JoinSet<()>,
+    registry: &JobRegistry,
+    grace_period: Duration,
+) {
+    if in_flight.is_empty() {
+        return;
+    }
+
+    println!(
+        "Waiting up to {:?} for {} in-flight job(s) to finish...",
+        grace_period,
+        in_flight.len()
+    );
+    if timeout(grace_period, join_all(in_flight)).await.is_ok() {
+        return;
+    }
+
+    eprintln!(
+        "Grace period elapsed with {} job(s) still running, requeueing them",
+        in_flight.len()
+    );
+    registry.cancel_all(CancelReason::Shutdown);
+
+    if timeout(CANCELLED_JOB_WAIT, join_all(in_flight))
+        .await
+        .is_err()
+    {
+        // Anything still unacked is requeued by the broker once the channel closes.
+        eprintln!("Aborting {} unresponsive job(s)", in_flight.len());
+        in_flight.abort_all();
+        join_all(in_flight).await;
+    }
+}
+
+async fn join_all(in_flight: &mut JoinSet<()>) {
+    while in_flight.join_next().await.is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::drain_jobs;
+    use crate::lib::cancellation::{CancelReason, JobRegistry};
+    use std::time::Duration;
+    use tokio::task::JoinSet;
+
+    #[tokio::test]
+    async fn lets_short_jobs_finish_without_cancelling() {
+        let registry = JobRegistry::new();
+        let job = registry.start("job-1").expect("job should start");
+        let token = job.token();
+        let mut in_flight = JoinSet::new();
+        in_flight.spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(job);
+        });
+
+        drain_jobs(&mut in_flight, &registry, Duration::from_secs(1)).await;
+
+        assert!(in_flight.is_empty());
+        assert_eq!(token.reason(), None);
+    }
+
+    #[tokio::test]
+    async fn cancels_jobs_that_outlive_the_grace_period() {
+        let registry = JobRegistry::new();
+        let job = registry.start("job-1").expect("job should start");
+        let token = job.token();
+        let mut in_flight = JoinSet::new();
+        in_flight.spawn(async move {
+            while job.token().reason().is_none() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+
+        drain_jobs(&mut in_flight, &registry, Duration::from_millis(20)).await;
+
+        assert!(in_flight.is_empty());
+        assert_eq!(token.reason(), Some(CancelReason::Shutdown));
+    }
+}
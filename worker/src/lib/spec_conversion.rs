@@ -0,0 +1,149 @@
+/// Brings decoded audio to the output's channel count and sample rate, so a
+/// stream that changes spec midway (some MP3 and chained Ogg files do) still
+/// produces one consistent file. Buffers are kept between calls.
+pub struct SpecConverter {
+    channels: usize,
+    sample_rate: u32,
+    /// Last input frame of the previous call, already remapped, so
+    /// interpolation continues smoothly across packets.
+    previous: Vec<f32>,
+    /// Where the next output frame falls, in input frames after `previous`.
+    position: f64,
+    remapped: Vec<f32>,
+    output: Vec<f32>,
+}
+
+impl SpecConverter {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            channels: channels.max(1),
+            sample_rate,
+            previous: Vec::new(),
+            position: 0.0,
+            remapped: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Converts interleaved `samples` with the given spec. Samples already in
+    /// the output spec are passed through untouched.
+    pub fn convert<'a>(
+        &'a mut self,
+        samples: &'a mut [f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> &'a mut [f32] {
+        let channels = channels.max(1);
+        if channels == self.channels && sample_rate == self.sample_rate {
+            self.previous.clear();
+            self.position = 0.0;
+            return samples;
+        }
+
+        self.remapped.clear();
+        self.remapped.extend_from_slice(&self.previous);
+        for frame in samples.chunks_exact(channels) {
+            remap_frame(frame, self.channels, &mut self.remapped);
+        }
+
+        self.output.clear();
+        if sample_rate == self.sample_rate {
+            self.output
+                .extend_from_slice(&self.remapped[self.previous.len()..]);
+            self.previous.clear();
+            self.position = 0.0;
+        } else {
+            self.resample(sample_rate);
+        }
+        &mut self.output
+    }
+
+    /// Linear interpolation from `input_rate` to the output rate.
+    fn resample(&mut self, input_rate: u32) {
+        let channels = self.channels;
+        let frames = self.remapped.len() / channels;
+        if frames == 0 {
+            return;
+        }
+        let step = f64::from(input_rate) / f64::from(self.sample_rate.max(1));
+
+        let mut position = self.position;
+        while position + 1.0 < frames as f64 {
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            let current = &self.remapped[index * channels..(index + 1) * channels];
+            let next = &self.remapped[(index + 1) * channels..(index + 2) * channels];
+            for (a, b) in current.iter().zip(next) {
+                self.output.push(a + (b - a) * fraction);
+            }
+            position += step;
+        }
+
+        self.position = position - (frames - 1) as f64;
+        self.previous.clear();
+        self.previous
+            .extend_from_slice(&self.remapped[(frames - 1) * channels..]);
+    }
+}
+
+/// Appends `frame` with `channels` channels. Extra input channels are averaged
+/// into the output channel they wrap around to; missing ones repeat the input.
+fn remap_frame(frame: &[f32], channels: usize, out: &mut Vec<f32>) {
+    for channel in 0..channels {
+        if frame.len() <= channels {
+            out.push(frame[channel % frame.len()]);
+        } else {
+            let folded = frame.iter().skip(channel).step_by(channels);
+            let count = folded.clone().count() as f32;
+            out.push(folded.sum::<f32>() / count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpecConverter;
+
+    #[test]
+    fn passes_matching_audio_through() {
+        let mut converter = SpecConverter::new(2, 44_100);
+        let mut samples = vec![0.1, 0.2, 0.3, 0.4];
+
+        assert_eq!(
+            converter.convert(&mut samples, 2, 44_100),
+            &[0.1, 0.2, 0.3, 0.4]
+        );
+    }
+
+    #[test]
+    fn remaps_channels() {
+        let mut converter = SpecConverter::new(2, 8_000);
+        assert_eq!(
+            converter.convert(&mut [0.5, -0.5], 1, 8_000),
+            &[0.5, 0.5, -0.5, -0.5]
+        );
+
+        let mut converter = SpecConverter::new(1, 8_000);
+        assert_eq!(
+            converter.convert(&mut [0.25, 0.75, 0.5, 1.0], 2, 8_000),
+            &[0.5, 0.75]
+        );
+    }
+
+    #[test]
+    fn resamples_continuously_across_packets() {
+        let mut converter = SpecConverter::new(1, 8_000);
+        let mut produced = Vec::new();
+        let mut input: Vec<f32> = (0..16_000).map(|n| n as f32).collect();
+        for packet in input.chunks_mut(1_000) {
+            produced.extend_from_slice(converter.convert(packet, 1, 16_000));
+        }
+
+        // Half the rate, so every other input sample, without gaps at
+        // packet boundaries.
+        assert!((7_999..=8_000).contains(&produced.len()));
+        for (n, sample) in produced.iter().enumerate() {
+            assert_eq!(*sample, (n * 2) as f32);
+        }
+    }
+}
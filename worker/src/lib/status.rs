@@ -0,0 +1,278 @@
+use lapin::Channel;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::lib::audio_processor::AudioSummary;
+use crate::lib::broker::{STATUS_QUEUE, publish};
+use crate::lib::cache::CacheOutcome;
+use crate::lib::markers::Marker;
+use crate::lib::storage::StorageResult;
+use crate::lib::tags::ArtworkInfo;
+
+/// Minimum time between two progress messages for the same job.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Progress changes smaller than this (in percent) are not worth a message.
+const PROGRESS_MIN_STEP: f32 = 1.0;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Received,
+    Processing,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatusError {
+    pub code: String,
+    pub message: String,
+}
+
+/// What decoding found out about the input. Only sent when the job actually
+/// decoded it, not when its output came from the cache.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AudioAnalysis {
+    /// Position of the rendered track among the input's audio tracks.
+    pub track_index: usize,
+    pub track_id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub duration_seconds: f64,
+    /// Times the input switched sample rate or channel count midway.
+    pub spec_changes: u32,
+    /// Tags of the input, keyed by snake_case name, e.g. `track_title`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artwork: Option<ArtworkInfo>,
+    /// Markers written to the output: the input's, the job's and detected
+    /// ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub markers: Vec<Marker>,
+}
+
+impl From<&AudioSummary> for AudioAnalysis {
+    fn from(summary: &AudioSummary) -> Self {
+        Self {
+            track_index: summary.track_index,
+            track_id: summary.track_id,
+            language: summary.language.clone(),
+            sample_rate: summary.sample_rate,
+            channels: summary.channels,
+            duration_seconds: summary.duration_secs(),
+            spec_changes: summary.spec_changes,
+            tags: summary.tags.clone(),
+            artwork: summary.artwork.clone(),
+            markers: summary
+                .markers
+                .iter()
+                .map(|cue| Marker::from_cue(cue, summary.sample_rate))
+                .collect(),
+        }
+    }
+}
+
+/// One output of a job that rendered every audio track.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrackOutput {
+    #[serde(flatten)]
+    pub output: StorageResult,
+    pub analysis: AudioAnalysis,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobStatusMessage {
+    pub job_id: String,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_size_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_sha256: Option<String>,
+    /// Set on `completed` when the output cache was consulted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub analysis: Option<AudioAnalysis>,
+    /// Every output, in track order, when the job rendered all tracks. The
+    /// `output_*` fields describe the first one.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tracks: Vec<TrackOutput>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<StatusError>,
+}
+
+impl JobStatusMessage {
+    fn new(job_id: String, status: JobStatus) -> Self {
+        Self {
+            job_id,
+            status,
+            progress: None,
+            output_key: None,
+            output_url: None,
+            output_size_bytes: None,
+            output_etag: None,
+            output_sha256: None,
+            cache: None,
+            analysis: None,
+            tracks: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn received(job_id: String) -> Self {
+        Self::new(job_id, JobStatus::Received)
+    }
+
+    pub fn processing(job_id: String, progress: f32) -> Self {
+        Self {
+            progress: Some(progress),
+            ..Self::new(job_id, JobStatus::Processing)
+        }
+    }
+
+    pub fn completed(job_id: String, stored_output: StorageResult) -> Self {
+        Self {
+            output_key: Some(stored_output.output_key),
+            output_url: stored_output.output_url,
+            output_size_bytes: Some(stored_output.output_size_bytes),
+            output_etag: stored_output.output_etag,
+            output_sha256: stored_output.output_sha256,
+            ..Self::new(job_id, JobStatus::Completed)
+        }
+    }
+
+    pub fn cancelled(job_id: String) -> Self {
+        Self::new(job_id, JobStatus::Cancelled)
+    }
+
+    pub fn failed(job_id: String, code: &str, message: String) -> Self {
+        Self {
+            error: Some(StatusError {
+                code: code.to_string(),
+                message,
+            }),
+            ..Self::new(job_id, JobStatus::Failed)
+        }
+    }
+}
+
+pub async fn publish_status(
+    channel: &Channel,
+    status: &JobStatusMessage,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let payload = serde_json::to_vec(status)?;
+    publish(
+        channel,
+        "",
+        STATUS_QUEUE,
+        &payload,
+        lapin::BasicProperties::default(),
+    )
+    .await
+}
+
+/// Handed to the decode loop, which runs on a blocking thread and must never
+/// wait on the broker. It only records the latest percentage.
+pub struct ProgressSender(watch::Sender<f32>);
+
+impl ProgressSender {
+    /// A sender nobody listens to.
+    #[cfg(test)]
+    pub fn detached() -> Self {
+        Self(watch::channel(0.0).0)
+    }
+
+    pub fn report(&self, frames_done: u64, total_frames: u64) {
+        if total_frames == 0 {
+            return;
+        }
+        let percent = (frames_done as f64 / total_frames as f64 * 100.0).min(100.0) as f32;
+        self.0.send_replace(percent);
+    }
+}
+
+/// Publishes `processing` messages for `job_id` as progress comes in, at most
+/// once per `PROGRESS_INTERVAL`. Abort the returned task before sending the
+/// final status so no progress message can arrive after it.
+pub fn spawn_progress_publisher(
+    channel: Channel,
+    job_id: String,
+) -> (ProgressSender, JoinHandle<()>) {
+    let (sender, mut updates) = watch::channel(0.0f32);
+
+    let task = tokio::spawn(async move {
+        let mut last_published = 0.0f32;
+
+        while updates.changed().await.is_ok() {
+            let percent = *updates.borrow_and_update();
+            if !should_publish(last_published, percent) {
+                continue;
+            }
+
+            let message = JobStatusMessage::processing(job_id.clone(), percent.floor());
+            if let Err(e) = publish_status(&channel, &message).await {
+                eprintln!("Failed to publish progress for job {}: {}", job_id, e);
+            }
+            last_published = percent;
+
+            tokio::time::sleep(PROGRESS_INTERVAL).await;
+        }
+    });
+
+    (ProgressSender(sender), task)
+}
+
+fn should_publish(last_published: f32, percent: f32) -> bool {
+    percent - last_published >= PROGRESS_MIN_STEP
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobStatusMessage, should_publish};
+    use serde_json::json;
+
+    #[test]
+    fn serializes_failed_status_with_error_details() {
+        let message = JobStatusMessage::failed("job-1".into(), "invalid_job", "bad json".into());
+
+        assert_eq!(
+            serde_json::to_value(&message).expect("status should serialize"),
+            json!({
+                "job_id": "job-1",
+                "status": "failed",
+                "error": { "code": "invalid_job", "message": "bad json" }
+            })
+        );
+    }
+
+    #[test]
+    fn serializes_progress_as_processing() {
+        let message = JobStatusMessage::processing("job-1".into(), 42.0);
+
+        assert_eq!(
+            serde_json::to_value(&message).expect("status should serialize"),
+            json!({ "job_id": "job-1", "status": "processing", "progress": 42.0 })
+        );
+    }
+
+    #[test]
+    fn skips_progress_steps_below_one_percent() {
+        assert!(!should_publish(10.0, 10.5));
+        assert!(should_publish(10.0, 11.0));
+    }
+}
@@ -0,0 +1,202 @@
+use async_trait::async_trait;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::lib::atomic_file::{PartialFile, copy_atomically};
+
+use super::{ObjectChunk, ObjectMetadata, StorageBackend, StoredObject, sanitize_relative_path};
+
+/// Stores objects as files under `root`, e.g. a volume shared with the API.
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn path_for(&self, key: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.root.join(sanitize_relative_path(Path::new(key))?))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn put(
+        &self,
+        key: &str,
+        source: &Path,
+        _metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>> {
+        let destination = self.path_for(key)?;
+        let source = source.to_path_buf();
+        // Renames, copies and fsyncs block, so they stay off the runtime.
+        let size = tokio::task::spawn_blocking(move || move_file(&source, &destination)).await??;
+
+        Ok(StoredObject { size, etag: None })
+    }
+
+    async fn get(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<ObjectChunk, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.path_for(key)?;
+        Ok(tokio::task::spawn_blocking(move || read_range(&path, range)).await??)
+    }
+
+    /// Hard-links when possible, so cached outputs take no extra space.
+    async fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        _metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>> {
+        let source = self.path_for(from)?;
+        let destination = self.path_for(to)?;
+        let size = tokio::task::spawn_blocking(move || link_file(&source, &destination)).await??;
+
+        Ok(StoredObject { size, etag: None })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.path_for(key)?;
+        match tokio::task::spawn_blocking(move || fs::remove_file(path)).await? {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.path_for(key)?;
+        Ok(tokio::task::spawn_blocking(move || path.try_exists()).await??)
+    }
+
+    /// Local files are served by the API, which signs its own download links.
+    async fn presign(
+        &self,
+        _key: &str,
+        _expires_in: Duration,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(None)
+    }
+}
+
+/// Moves `source` to `destination` and returns its size. Renames replace the
+/// destination atomically; readers see either the old object or the new one,
+/// never a partial file.
+fn move_file(source: &Path, destination: &Path) -> io::Result<u64> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::rename(source, destination) {
+        Ok(_) => {}
+        Err(error) if is_cross_device_error(&error) => {
+            copy_atomically(source, destination)?;
+            fs::remove_file(source)?;
+        }
+        Err(error) => return Err(error),
+    }
+    Ok(fs::metadata(destination)?.len())
+}
+
+/// Hard-links `source` to `destination`, or copies it where linking fails,
+/// and returns its size.
+fn link_file(source: &Path, destination: &Path) -> io::Result<u64> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let partial = PartialFile::new(destination);
+    if fs::hard_link(source, partial.path()).is_err() {
+        fs::copy(source, partial.path())?;
+    }
+    partial.commit()?;
+    Ok(fs::metadata(destination)?.len())
+}
+
+fn read_range(path: &Path, range: Range<u64>) -> io::Result<ObjectChunk> {
+    let mut file = File::open(path)?;
+    let total_len = file.metadata()?.len();
+    let start = range.start.min(total_len);
+    let end = range.end.min(total_len);
+
+    let mut data = Vec::with_capacity(end.saturating_sub(start) as usize);
+    file.seek(SeekFrom::Start(start))?;
+    file.take(end.saturating_sub(start))
+        .read_to_end(&mut data)?;
+
+    Ok(ObjectChunk { data, total_len })
+}
+
+fn is_cross_device_error(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(18)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalBackend;
+    use crate::lib::storage::{ObjectMetadata, StorageBackend};
+    use std::fs;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn unique_temp_dir(name: &str) -> std::path::PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should be monotonic")
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("worker-storage-{name}-{nanos}"))
+    }
+
+    #[tokio::test]
+    async fn stores_reads_and_deletes_files() {
+        let base_dir = unique_temp_dir("local");
+        let temp_file = base_dir.join("tmp").join("job-1.wav");
+        let storage_dir = base_dir.join("storage");
+        fs::create_dir_all(temp_file.parent().expect("temp parent should exist"))
+            .expect("temp dir should be created");
+        fs::write(&temp_file, b"wave-data").expect("temp file should be written");
+
+        let storage = LocalBackend::new(storage_dir.clone());
+        let stored = storage
+            .put(
+                "processed/job-1.wav",
+                &temp_file,
+                &ObjectMetadata::default(),
+            )
+            .await
+            .expect("file should be stored locally");
+
+        assert_eq!(stored.size, 9);
+        assert!(!temp_file.exists());
+        assert!(storage_dir.join("processed/job-1.wav").exists());
+
+        let chunk = storage
+            .get("processed/job-1.wav", 5..100)
+            .await
+            .expect("range should be readable");
+        assert_eq!(chunk.data, b"data");
+        assert_eq!(chunk.total_len, 9);
+
+        storage
+            .delete("processed/job-1.wav")
+            .await
+            .expect("file should be deleted");
+        assert!(
+            !storage
+                .exists("processed/job-1.wav")
+                .await
+                .expect("exists should work")
+        );
+        assert!(storage.get("../escape.wav", 0..1).await.is_err());
+
+        let _ = fs::remove_dir_all(base_dir);
+    }
+}
@@ -0,0 +1,98 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{ObjectChunk, ObjectMetadata, StorageBackend, StoredObject};
+
+/// Keeps objects in memory. Meant for tests and local experiments; everything
+/// is lost when the worker stops.
+#[derive(Default)]
+pub struct MemoryBackend {
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    fn objects(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.objects
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn put(
+        &self,
+        key: &str,
+        source: &Path,
+        _metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>> {
+        let data = std::fs::read(source)?;
+        std::fs::remove_file(source)?;
+        let size = data.len() as u64;
+        self.objects().insert(key.to_string(), data);
+        Ok(StoredObject { size, etag: None })
+    }
+
+    async fn get(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<ObjectChunk, Box<dyn std::error::Error + Send + Sync>> {
+        let objects = self.objects();
+        let Some(object) = objects.get(key) else {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no object stored under {key}"),
+            )));
+        };
+
+        let total_len = object.len() as u64;
+        let start = range.start.min(total_len) as usize;
+        let end = range.end.min(total_len) as usize;
+
+        Ok(ObjectChunk {
+            data: object[start..end.max(start)].to_vec(),
+            total_len,
+        })
+    }
+
+    async fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        _metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>> {
+        let mut objects = self.objects();
+        let Some(object) = objects.get(from).cloned() else {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no object stored under {from}"),
+            )));
+        };
+        let size = object.len() as u64;
+        objects.insert(to.to_string(), object);
+        Ok(StoredObject { size, etag: None })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.objects().remove(key);
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.objects().contains_key(key))
+    }
+
+    async fn presign(
+        &self,
+        _key: &str,
+        _expires_in: Duration,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(None)
+    }
+}
@@ -0,0 +1,482 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod local;
+mod memory;
+mod multipart;
+mod s3;
+mod unavailable;
+
+pub use local::LocalBackend;
+pub use memory::MemoryBackend;
+pub use multipart::MultipartConfig;
+pub use s3::{S3Backend, S3Config};
+pub use unavailable::UnavailableBackend;
+
+use crate::lib::error::WorkerError;
+use crate::lib::idempotency::to_hex;
+
+const DEFAULT_LOCAL_STORAGE_ROOT: &str = "/app/data";
+/// Bytes read per request when re-reading a stored object to verify it.
+const VERIFY_CHUNK_BYTES: u64 = 8 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StorageResult {
+    pub output_key: String,
+    pub output_url: Option<String>,
+    pub output_size_bytes: u64,
+    #[serde(default)]
+    pub output_etag: Option<String>,
+    /// Hex SHA-256 of the stored bytes, when the worker wrote them itself.
+    #[serde(default)]
+    pub output_sha256: Option<String>,
+}
+
+/// Describes an object being written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectMetadata {
+    /// Stored as user metadata (`x-amz-meta-*` on S3).
+    pub user: BTreeMap<String, String>,
+    pub content_disposition: Option<String>,
+    /// SHA-256 of the content. Backends that support it have the store check
+    /// the upload against it.
+    pub sha256: Option<[u8; 32]>,
+}
+
+/// The object a `put` or `copy` produced.
+pub struct StoredObject {
+    pub size: u64,
+    /// Only reported by backends that have one, e.g. S3.
+    pub etag: Option<String>,
+}
+
+/// Part of a stored object, along with the size of the whole object.
+pub struct ObjectChunk {
+    pub data: Vec<u8>,
+    pub total_len: u64,
+}
+
+/// Where processed outputs are kept and remote inputs are read from. Keys are
+/// relative, `/`-separated paths such as `processed/job-1.wav`.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Moves the file at `source` into storage under `key`. `source` no
+    /// longer exists afterwards. Backends without object metadata ignore
+    /// `metadata`.
+    async fn put(
+        &self,
+        key: &str,
+        source: &Path,
+        metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Reads `range` of the object at `key`, clamped to the object's size.
+    async fn get(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<ObjectChunk, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Copies the object at `from` to `to` with `metadata`, replacing
+    /// anything already there.
+    async fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Deleting a missing object is not an error.
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// A time-limited download URL for `key`, or `None` when the backend has
+    /// no way to hand out URLs.
+    async fn presign(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Selects and configures the storage backend.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "driver", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Local {
+        #[serde(default = "local_storage_root")]
+        root: PathBuf,
+    },
+    /// Any S3-compatible store: AWS, Cloudflare R2, MinIO.
+    #[serde(alias = "r2")]
+    S3(S3Config),
+    Memory,
+    /// The configuration could not be read. Jobs fail with `reason` until
+    /// the worker is restarted with a working one.
+    #[serde(skip)]
+    Unavailable {
+        reason: String,
+    },
+}
+
+impl StorageConfig {
+    /// Reads the JSON file named by `STORAGE_CONFIG_FILE` when it is set, and
+    /// otherwise picks the backend from `AUDIO_STORAGE_DRIVER`.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        if let Ok(path) = std::env::var("STORAGE_CONFIG_FILE") {
+            let raw = std::fs::read_to_string(&path)
+                .map_err(|e| format!("cannot read STORAGE_CONFIG_FILE {path}: {e}"))?;
+            return serde_json::from_str(&raw)
+                .map_err(|e| format!("invalid STORAGE_CONFIG_FILE {path}: {e}").into());
+        }
+
+        let raw = std::env::var("AUDIO_STORAGE_DRIVER").unwrap_or_else(|_| "r2".into());
+
+        match raw.trim().to_lowercase().as_str() {
+            "local" => Ok(Self::Local {
+                root: local_storage_root(),
+            }),
+            "r2" => Ok(Self::S3(S3Config::r2_from_env()?)),
+            "s3" => Ok(Self::S3(S3Config::s3_from_env()?)),
+            "memory" => Ok(Self::Memory),
+            value => Err(format!("unsupported AUDIO_STORAGE_DRIVER: {value}").into()),
+        }
+    }
+
+    pub fn build(&self) -> Arc<dyn StorageBackend> {
+        match self {
+            StorageConfig::Local { root } => Arc::new(LocalBackend::new(root.clone())),
+            StorageConfig::S3(config) => Arc::new(S3Backend::new(config)),
+            StorageConfig::Memory => Arc::new(MemoryBackend::default()),
+            StorageConfig::Unavailable { reason } => {
+                Arc::new(UnavailableBackend::new(reason.clone()))
+            }
+        }
+    }
+}
+
+/// Stores the output of a job and, when the backend supports it, attaches a
+/// download URL valid for `url_ttl`.
+pub async fn persist_output(
+    storage: &dyn StorageBackend,
+    temp_file_path: &Path,
+    desired_output_path: &Path,
+    metadata: &ObjectMetadata,
+    url_ttl: Duration,
+) -> Result<StorageResult, Box<dyn std::error::Error + Send + Sync>> {
+    let output_key = sanitize_relative_path(desired_output_path)?;
+    let stored = storage
+        .put(
+            &output_key,
+            temp_file_path,
+            &metadata.with_file_name(&output_key),
+        )
+        .await?;
+
+    stored_result(storage, output_key, stored, metadata, url_ttl).await
+}
+
+/// Stores a copy of an existing object, e.g. a cached output, as the output
+/// of a job.
+pub async fn copy_output(
+    storage: &dyn StorageBackend,
+    existing_key: &str,
+    desired_output_path: &Path,
+    metadata: &ObjectMetadata,
+    url_ttl: Duration,
+) -> Result<StorageResult, Box<dyn std::error::Error + Send + Sync>> {
+    let output_key = sanitize_relative_path(desired_output_path)?;
+    let stored = storage
+        .copy(
+            existing_key,
+            &output_key,
+            &metadata.with_file_name(&output_key),
+        )
+        .await?;
+
+    stored_result(storage, output_key, stored, metadata, url_ttl).await
+}
+
+async fn stored_result(
+    storage: &dyn StorageBackend,
+    output_key: String,
+    stored: StoredObject,
+    metadata: &ObjectMetadata,
+    url_ttl: Duration,
+) -> Result<StorageResult, Box<dyn std::error::Error + Send + Sync>> {
+    let output_url = storage.presign(&output_key, url_ttl).await?;
+
+    Ok(StorageResult {
+        output_key,
+        output_url,
+        output_size_bytes: stored.size,
+        output_etag: stored.etag,
+        output_sha256: metadata.sha256.map(|digest| to_hex(&digest)),
+    })
+}
+
+/// Re-reads the object at `key` and checks it hashes to `expected`. An object
+/// that does not is deleted, so nobody downloads it.
+pub async fn verify_stored(
+    storage: &dyn StorageBackend,
+    key: &str,
+    expected: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    loop {
+        let chunk = storage
+            .get(key, offset..offset + VERIFY_CHUNK_BYTES)
+            .await?;
+        hasher.update(&chunk.data);
+        offset += chunk.data.len() as u64;
+        if chunk.data.is_empty() || offset >= chunk.total_len {
+            break;
+        }
+    }
+
+    let actual: [u8; 32] = hasher.finalize().into();
+    if &actual != expected {
+        if let Err(e) = storage.delete(key).await {
+            eprintln!("Failed to delete corrupt object {}: {}", key, e);
+        }
+        return Err(Box::new(ChecksumMismatch {
+            key: key.to_string(),
+            expected: to_hex(expected),
+            actual: to_hex(&actual),
+        }));
+    }
+    Ok(())
+}
+
+/// A stored object does not hash to the digest computed when it was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub key: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checksum mismatch for {}: expected sha256 {}, stored object has {}",
+            self.key, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+impl ObjectMetadata {
+    /// Adds a `Content-Disposition` that downloads the object under the last
+    /// segment of `key`, unless one is set already.
+    fn with_file_name(&self, key: &str) -> Self {
+        let mut metadata = self.clone();
+        if metadata.content_disposition.is_none() {
+            let file_name = key.rsplit('/').next().unwrap_or(key);
+            metadata.content_disposition = Some(content_disposition(file_name));
+        }
+        metadata
+    }
+}
+
+/// `attachment` disposition with an ASCII fallback name and the exact name
+/// in RFC 5987 form.
+fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    format!(
+        "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+        percent_encode(file_name)
+    )
+}
+
+/// Percent-encodes everything except RFC 3986 unreserved characters and `/`.
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+pub fn local_storage_root() -> PathBuf {
+    std::env::var("LOCAL_AUDIO_STORAGE_ROOT")
+        .unwrap_or_else(|_| DEFAULT_LOCAL_STORAGE_ROOT.into())
+        .into()
+}
+
+fn sanitize_relative_path(path: &Path) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(Box::new(WorkerError::InvalidJob(format!(
+                    "invalid output path: {}",
+                    path.display()
+                ))));
+            }
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        return Err(Box::new(WorkerError::InvalidJob(
+            "output path cannot be empty".into(),
+        )));
+    }
+
+    Ok(normalized.to_string_lossy().replace('\\', "/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ChecksumMismatch, MemoryBackend, ObjectMetadata, StorageBackend, StorageConfig,
+        content_disposition, copy_output, persist_output, sanitize_relative_path, verify_stored,
+    };
+    use crate::lib::idempotency::to_hex;
+    use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn unique_temp_file(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should be monotonic")
+            .as_nanos();
+
+        std::env::temp_dir().join(format!("worker-storage-{name}-{nanos}.wav"))
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        let path = Path::new("../escape.wav");
+
+        let error = sanitize_relative_path(path).expect_err("path should be rejected");
+
+        assert!(error.to_string().contains("invalid output path"));
+    }
+
+    #[test]
+    fn builds_download_dispositions() {
+        assert_eq!(
+            content_disposition("job 1.wav"),
+            "attachment; filename=\"job_1.wav\"; filename*=UTF-8''job%201.wav"
+        );
+        assert_eq!(
+            content_disposition("çé\".wav"),
+            "attachment; filename=\"___.wav\"; filename*=UTF-8''%C3%A7%C3%A9%22.wav"
+        );
+    }
+
+    #[test]
+    fn parses_storage_config_files() {
+        let config: StorageConfig = serde_json::from_str(
+            r#"{"driver":"r2","endpoint":"https://r2.example.com","access_key_id":"id","secret_access_key":"secret","bucket":"out"}"#,
+        )
+        .expect("r2 config should parse");
+        let StorageConfig::S3(s3) = config else {
+            panic!("r2 should map to the S3 backend");
+        };
+        assert_eq!(s3.bucket, "out");
+        assert_eq!(s3.region, "auto");
+        assert!(s3.force_path_style);
+
+        let config: StorageConfig = serde_json::from_str(r#"{"driver":"local","root":"/srv"}"#)
+            .expect("local config should parse");
+        assert!(
+            config
+                == StorageConfig::Local {
+                    root: "/srv".into()
+                }
+        );
+    }
+
+    #[tokio::test]
+    async fn persists_output_under_its_sanitized_key() {
+        let storage = MemoryBackend::default();
+        let temp_file = unique_temp_file("persist");
+        fs::write(&temp_file, b"wave-data").expect("temp file should be written");
+
+        let digest: [u8; 32] = Sha256::digest(b"wave-data").into();
+        let metadata = ObjectMetadata {
+            sha256: Some(digest),
+            ..ObjectMetadata::default()
+        };
+        let ttl = Duration::from_secs(60);
+        let result = persist_output(
+            &storage,
+            &temp_file,
+            Path::new("./processed/job-1.wav"),
+            &metadata,
+            ttl,
+        )
+        .await
+        .expect("output should be stored");
+
+        assert_eq!(result.output_key, "processed/job-1.wav");
+        assert_eq!(result.output_size_bytes, 9);
+        assert!(result.output_url.is_none());
+        assert_eq!(result.output_sha256, Some(to_hex(&digest)));
+        verify_stored(&storage, "processed/job-1.wav", &digest)
+            .await
+            .expect("stored object should match its digest");
+        assert!(!temp_file.exists());
+
+        let copied = copy_output(
+            &storage,
+            "processed/job-1.wav",
+            Path::new("processed/job-2.wav"),
+            &metadata,
+            ttl,
+        )
+        .await
+        .expect("output should be copied");
+        assert_eq!(copied.output_size_bytes, 9);
+        assert!(
+            storage
+                .exists("processed/job-2.wav")
+                .await
+                .expect("exists should work")
+        );
+
+        let error = verify_stored(&storage, "processed/job-2.wav", &[0; 32])
+            .await
+            .expect_err("a wrong digest should be reported");
+        assert!(error.downcast_ref::<ChecksumMismatch>().is_some());
+        assert!(
+            !storage
+                .exists("processed/job-2.wav")
+                .await
+                .expect("exists should work"),
+            "a corrupt object should be deleted"
+        );
+    }
+}
@@ -0,0 +1,369 @@
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart};
+use serde::Deserialize;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::task::JoinSet;
+
+use super::ObjectMetadata;
+use super::s3::{object_error, user_metadata};
+use crate::lib::config::env_or;
+
+/// S3 rejects parts smaller than this, except for the last one.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// S3 allows at most this many parts per upload.
+const MAX_PARTS: u64 = 10_000;
+/// `CopyObject` refuses sources larger than this; they are copied in parts.
+pub const MAX_SINGLE_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+const DEFAULT_PART_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_PART_RETRIES: u32 = 3;
+const PART_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// How outputs larger than one part are uploaded.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MultipartConfig {
+    /// Outputs up to this size go up in a single `PutObject`; larger ones are
+    /// split into parts of this size.
+    pub part_size: u64,
+    /// Parts in flight at the same time. Each one streams from disk, so this
+    /// bounds connections rather than memory.
+    pub concurrency: usize,
+    /// Attempts per part after the first one before the upload is aborted.
+    pub part_retries: u32,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        Self {
+            part_size: DEFAULT_PART_SIZE,
+            concurrency: DEFAULT_CONCURRENCY,
+            part_retries: DEFAULT_PART_RETRIES,
+        }
+    }
+}
+
+impl MultipartConfig {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self {
+            part_size: env_or("STORAGE_MULTIPART_PART_SIZE_BYTES", DEFAULT_PART_SIZE)?,
+            concurrency: env_or("STORAGE_MULTIPART_CONCURRENCY", DEFAULT_CONCURRENCY)?,
+            part_retries: env_or("STORAGE_MULTIPART_PART_RETRIES", DEFAULT_PART_RETRIES)?,
+        })
+    }
+
+    /// The part size actually used for `size` bytes: at least the S3 minimum,
+    /// and large enough to stay under the part count limit.
+    fn part_size_for(&self, size: u64) -> u64 {
+        self.part_size
+            .max(MIN_PART_SIZE)
+            .max(size.div_ceil(MAX_PARTS))
+    }
+
+    pub fn is_multipart(&self, size: u64) -> bool {
+        size > self.part_size.max(MIN_PART_SIZE)
+    }
+}
+
+/// Byte ranges `(offset, len)` of each part, in part number order.
+fn plan_parts(size: u64, part_size: u64) -> Vec<(u64, u64)> {
+    (0..size.div_ceil(part_size))
+        .map(|index| {
+            let offset = index * part_size;
+            (offset, part_size.min(size - offset))
+        })
+        .collect()
+}
+
+/// Where the parts of a multipart upload come from.
+pub enum PartSource {
+    /// A local file, read straight from disk.
+    File(PathBuf),
+    /// An object in the store, as an `x-amz-copy-source` value. Parts are
+    /// copied server-side with `UploadPartCopy`.
+    Object(String),
+}
+
+/// A multipart upload of one file or object. A failed part is retried on its
+/// own instead of restarting the whole upload.
+pub struct MultipartUpload {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    key: String,
+    source: PartSource,
+    config: MultipartConfig,
+    metadata: ObjectMetadata,
+}
+
+impl MultipartUpload {
+    pub fn new(
+        client: aws_sdk_s3::Client,
+        bucket: &str,
+        key: &str,
+        source: PartSource,
+        config: &MultipartConfig,
+        metadata: &ObjectMetadata,
+    ) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            source,
+            config: config.clone(),
+            metadata: metadata.clone(),
+        }
+    }
+
+    /// Stores `size` bytes of the source and returns the object's ETag. On
+    /// failure, or when the returned future is dropped, e.g. by a job
+    /// timeout, the upload is aborted so the store does not keep the orphaned
+    /// parts around.
+    pub async fn run(
+        self,
+        size: u64,
+        content_type: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let created = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .content_type(content_type)
+            .set_content_disposition(self.metadata.content_disposition.clone())
+            .set_metadata(Some(user_metadata(&self.metadata)))
+            .checksum_algorithm(ChecksumAlgorithm::Sha256)
+            .send()
+            .await
+            .map_err(|e| object_error("CreateMultipartUpload", &self.bucket, &self.key, e))?;
+        let upload_id = created
+            .upload_id()
+            .ok_or("CreateMultipartUpload returned no upload id")?
+            .to_string();
+
+        let upload = Arc::new(self);
+        let mut guard = AbortOnDrop {
+            upload: Arc::clone(&upload),
+            upload_id: Some(upload_id.clone()),
+        };
+        let result = Arc::clone(&upload).upload_parts(&upload_id, size).await;
+        guard.upload_id = None;
+        if result.is_err() {
+            upload.abort(&upload_id).await;
+        }
+        result
+    }
+
+    async fn upload_parts(
+        self: Arc<Self>,
+        upload_id: &str,
+        size: u64,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let part_size = self.config.part_size_for(size);
+        let plan = plan_parts(size, part_size);
+        println!(
+            "Storing {} in {} parts of {} bytes",
+            self.key,
+            plan.len(),
+            part_size
+        );
+
+        // Dropping the set on an early return cancels the parts still running.
+        let mut in_flight = JoinSet::new();
+        let mut completed = Vec::with_capacity(plan.len());
+        for (index, (offset, len)) in plan.into_iter().enumerate() {
+            if in_flight.len() >= self.config.concurrency.max(1)
+                && let Some(part) = in_flight.join_next().await
+            {
+                completed.push(part??);
+            }
+
+            let upload = Arc::clone(&self);
+            let upload_id = upload_id.to_string();
+            let part_number = index as i32 + 1;
+            in_flight.spawn(async move {
+                upload
+                    .upload_part(&upload_id, part_number, offset, len)
+                    .await
+            });
+        }
+        while let Some(part) = in_flight.join_next().await {
+            completed.push(part??);
+        }
+        completed.sort_by_key(|part| part.part_number());
+
+        let completed = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(completed))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| object_error("CompleteMultipartUpload", &self.bucket, &self.key, e))?;
+
+        Ok(completed.e_tag)
+    }
+
+    async fn upload_part(
+        &self,
+        upload_id: &str,
+        part_number: i32,
+        offset: u64,
+        len: u64,
+    ) -> Result<CompletedPart, Box<dyn std::error::Error + Send + Sync>> {
+        let mut attempt = 0;
+        loop {
+            match self.send_part(upload_id, part_number, offset, len).await? {
+                Ok(part) => return Ok(part),
+                // Only outages are worth another attempt; a refused
+                // request will be refused again.
+                Err(error)
+                    if attempt < self.config.part_retries
+                        && error.kind() == io::ErrorKind::Other =>
+                {
+                    attempt += 1;
+                    let delay = PART_RETRY_BASE_DELAY * 2u32.saturating_pow(attempt - 1);
+                    eprintln!(
+                        "Part {} of {} failed ({}), retrying in {:?}",
+                        part_number, self.key, error, delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(error) => return Err(Box::new(error)),
+            }
+        }
+    }
+
+    /// One attempt at a part. The outer error is local and not worth a retry,
+    /// the inner one comes from the store.
+    async fn send_part(
+        &self,
+        upload_id: &str,
+        part_number: i32,
+        offset: u64,
+        len: u64,
+    ) -> Result<io::Result<CompletedPart>, Box<dyn std::error::Error + Send + Sync>> {
+        let part = CompletedPart::builder().part_number(part_number);
+        let part = match &self.source {
+            PartSource::File(path) => {
+                let body = ByteStream::read_from()
+                    .path(path)
+                    .offset(offset)
+                    .length(Length::Exact(len))
+                    .build()
+                    .await?;
+
+                self.client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .content_length(len as i64)
+                    .checksum_algorithm(ChecksumAlgorithm::Sha256)
+                    .body(body)
+                    .send()
+                    .await
+                    .map(|output| {
+                        part.set_e_tag(output.e_tag().map(str::to_string))
+                            .set_checksum_sha256(output.checksum_sha256().map(str::to_string))
+                    })
+                    .map_err(|e| object_error("UploadPart", &self.bucket, &self.key, e))
+            }
+            PartSource::Object(copy_source) => self
+                .client
+                .upload_part_copy()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .copy_source(copy_source)
+                .copy_source_range(format!("bytes={}-{}", offset, offset + len - 1))
+                .send()
+                .await
+                .map(|output| {
+                    let result = output.copy_part_result();
+                    part.set_e_tag(result.and_then(|r| r.e_tag()).map(str::to_string))
+                        .set_checksum_sha256(
+                            result.and_then(|r| r.checksum_sha256()).map(str::to_string),
+                        )
+                })
+                .map_err(|e| object_error("UploadPartCopy", &self.bucket, &self.key, e)),
+        };
+        Ok(part.map(|part| part.build()))
+    }
+
+    async fn abort(&self, upload_id: &str) {
+        let aborted = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(upload_id)
+            .send()
+            .await;
+
+        if let Err(e) = aborted {
+            eprintln!(
+                "Failed to abort multipart upload of {}: {}",
+                self.key,
+                object_error("AbortMultipartUpload", &self.bucket, &self.key, e)
+            );
+        }
+    }
+}
+
+/// Aborts the upload when `run` is dropped before it finished, which also
+/// drops the parts in flight. Cleared once `run` handles the outcome itself.
+struct AbortOnDrop {
+    upload: Arc<MultipartUpload>,
+    upload_id: Option<String>,
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        let Some(upload_id) = self.upload_id.take() else {
+            return;
+        };
+        let Ok(handle) = Handle::try_current() else {
+            return;
+        };
+        let upload = Arc::clone(&self.upload);
+        eprintln!("Multipart upload of {} abandoned, aborting it", upload.key);
+        handle.spawn(async move { upload.abort(&upload_id).await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MIN_PART_SIZE, MultipartConfig, plan_parts};
+
+    #[test]
+    fn plans_parts_covering_the_whole_file() {
+        assert_eq!(plan_parts(10, 4), vec![(0, 4), (4, 4), (8, 2)]);
+        assert_eq!(plan_parts(8, 4), vec![(0, 4), (4, 4)]);
+    }
+
+    #[test]
+    fn keeps_part_sizes_within_s3_limits() {
+        let config = MultipartConfig {
+            part_size: 1024,
+            ..MultipartConfig::default()
+        };
+        assert_eq!(config.part_size_for(100 * MIN_PART_SIZE), MIN_PART_SIZE);
+        assert!(!config.is_multipart(MIN_PART_SIZE));
+
+        let huge = 200 * 1024 * 1024 * 1024;
+        let part_size = MultipartConfig::default().part_size_for(huge);
+        assert!(huge.div_ceil(part_size) <= 10_000);
+    }
+}
@@ -0,0 +1,423 @@
+use async_trait::async_trait;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::config::{Builder, Credentials, RequestChecksumCalculation};
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::MetadataDirective;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+use super::multipart::{MAX_SINGLE_COPY_SIZE, MultipartConfig, MultipartUpload, PartSource};
+use super::{ObjectChunk, ObjectMetadata, StorageBackend, StoredObject, percent_encode};
+use crate::lib::config::env_or;
+use crate::lib::idempotency::to_hex;
+use crate::lib::media_source::parse_content_range_total;
+
+const DEFAULT_R2_BUCKET: &str = "processed-audio";
+
+/// Connection settings for an S3-compatible store.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+pub struct S3Config {
+    /// Custom endpoint for R2 or MinIO; AWS is used when unset.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub bucket: String,
+    #[serde(default = "default_force_path_style")]
+    pub force_path_style: bool,
+    #[serde(default)]
+    pub multipart: MultipartConfig,
+}
+
+impl S3Config {
+    /// Cloudflare R2, configured through the `CLOUDFLARE_*` variables shared
+    /// with the API.
+    pub fn r2_from_env() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self {
+            endpoint: Some(required_env("CLOUDFLARE_ENDPOINT")?),
+            region: default_region(),
+            access_key_id: required_env("CLOUDFLARE_ACCESS_KEY_ID")?,
+            secret_access_key: required_env("CLOUDFLARE_SECRET_ACCESS_KEY")?,
+            bucket: std::env::var("R2_BUCKET_NAME").unwrap_or_else(|_| DEFAULT_R2_BUCKET.into()),
+            force_path_style: true,
+            multipart: MultipartConfig::from_env()?,
+        })
+    }
+
+    /// AWS S3 or MinIO, configured through the `S3_*` variables.
+    pub fn s3_from_env() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self {
+            endpoint: std::env::var("S3_ENDPOINT").ok(),
+            region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".into()),
+            access_key_id: required_env("S3_ACCESS_KEY_ID")?,
+            secret_access_key: required_env("S3_SECRET_ACCESS_KEY")?,
+            bucket: required_env("S3_BUCKET")?,
+            force_path_style: env_or("S3_FORCE_PATH_STYLE", false)?,
+            multipart: MultipartConfig::from_env()?,
+        })
+    }
+
+    fn client(&self) -> aws_sdk_s3::Client {
+        let mut builder = Builder::new()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(self.region.clone()))
+            .credentials_provider(Credentials::new(
+                self.access_key_id.clone(),
+                self.secret_access_key.clone(),
+                None,
+                None,
+                "worker-config",
+            ))
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+            .force_path_style(self.force_path_style);
+
+        if let Some(endpoint) = &self.endpoint {
+            builder = builder.endpoint_url(endpoint);
+        }
+
+        aws_sdk_s3::Client::from_conf(builder.build())
+    }
+}
+
+fn default_region() -> String {
+    "auto".into()
+}
+
+fn default_force_path_style() -> bool {
+    true
+}
+
+fn required_env(name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    std::env::var(name).map_err(|_| format!("{name} is not set").into())
+}
+
+pub struct S3Backend {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    multipart: MultipartConfig,
+}
+
+impl S3Backend {
+    pub fn new(config: &S3Config) -> Self {
+        Self::for_bucket(config, &config.bucket)
+    }
+
+    /// Same endpoint and credentials, different bucket. Used for `s3://` and
+    /// `r2://` inputs, which name their own bucket.
+    pub fn for_bucket(config: &S3Config, bucket: &str) -> Self {
+        Self {
+            client: config.client(),
+            bucket: bucket.to_string(),
+            multipart: config.multipart.clone(),
+        }
+    }
+}
+
+impl S3Backend {
+    async fn stored_size(
+        &self,
+        key: &str,
+    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|error| object_error("HeadObject", &self.bucket, key, error))?;
+
+        Ok(head
+            .content_length()
+            .and_then(|len| u64::try_from(len).ok())
+            .unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn put(
+        &self,
+        key: &str,
+        source: &Path,
+        metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>> {
+        println!(
+            "Uploading processed audio: bucket={}, key={}, path={}",
+            self.bucket,
+            key,
+            source.display()
+        );
+
+        let size = std::fs::metadata(source)?.len();
+
+        let etag = if self.multipart.is_multipart(size) {
+            MultipartUpload::new(
+                self.client.clone(),
+                &self.bucket,
+                key,
+                PartSource::File(source.to_path_buf()),
+                &self.multipart,
+                metadata,
+            )
+            .run(size, content_type_for(key))
+            .await?
+        } else {
+            let body = ByteStream::from_path(source).await?;
+
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(body)
+                .content_type(content_type_for(key))
+                .set_content_disposition(metadata.content_disposition.clone())
+                .set_metadata(Some(user_metadata(metadata)))
+                .set_checksum_sha256(metadata.sha256.map(|digest| BASE64.encode(digest)))
+                .send()
+                .await
+                .map_err(|error| object_error("PutObject", &self.bucket, key, error))?
+                .e_tag
+        };
+
+        // The local file is the only other copy, so it stays until the store
+        // confirms it holds the whole object.
+        let stored_size = self.stored_size(key).await?;
+        if stored_size != size {
+            return Err(format!(
+                "s3://{}/{key} holds {stored_size} bytes after uploading {size}",
+                self.bucket
+            )
+            .into());
+        }
+
+        std::fs::remove_file(source)?;
+        println!("Successfully uploaded {}", key);
+        Ok(StoredObject { size, etag })
+    }
+
+    async fn get(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<ObjectChunk, Box<dyn std::error::Error + Send + Sync>> {
+        let mut request = self.client.get_object().bucket(&self.bucket).key(key);
+        if let Some(header) = range_header(&range) {
+            request = request.range(header);
+        }
+
+        let output = request
+            .send()
+            .await
+            .map_err(|error| object_error("GetObject", &self.bucket, key, error))?;
+
+        // Without a Content-Range the store sent the whole object.
+        let total_len = output
+            .content_range()
+            .and_then(parse_content_range_total)
+            .or_else(|| {
+                output
+                    .content_length()
+                    .and_then(|len| u64::try_from(len).ok())
+            })
+            .ok_or_else(|| format!("s3://{}/{key} did not report its size", self.bucket))?;
+        let data = output.body.collect().await?.into_bytes().to_vec();
+
+        Ok(ObjectChunk { data, total_len })
+    }
+
+    /// Server-side copy; the data never passes through the worker. Objects too
+    /// large for one `CopyObject` are copied in parts.
+    async fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>> {
+        let size = self.stored_size(from).await?;
+        if size > MAX_SINGLE_COPY_SIZE {
+            let etag = MultipartUpload::new(
+                self.client.clone(),
+                &self.bucket,
+                to,
+                PartSource::Object(copy_source(&self.bucket, from)),
+                &self.multipart,
+                metadata,
+            )
+            .run(size, content_type_for(to))
+            .await?;
+            return Ok(StoredObject {
+                size: self.stored_size(to).await?,
+                etag,
+            });
+        }
+
+        let copied = self
+            .client
+            .copy_object()
+            .bucket(&self.bucket)
+            .key(to)
+            .copy_source(copy_source(&self.bucket, from))
+            .metadata_directive(MetadataDirective::Replace)
+            .content_type(content_type_for(to))
+            .set_content_disposition(metadata.content_disposition.clone())
+            .set_metadata(Some(user_metadata(metadata)))
+            .send()
+            .await
+            .map_err(|error| object_error("CopyObject", &self.bucket, to, error))?;
+
+        Ok(StoredObject {
+            size: self.stored_size(to).await?,
+            etag: copied
+                .copy_object_result()
+                .and_then(|result| result.e_tag())
+                .map(str::to_string),
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|error| object_error("DeleteObject", &self.bucket, key, error))?;
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(error) => match object_error("HeadObject", &self.bucket, key, error) {
+                error if error.kind() == io::ErrorKind::NotFound => Ok(false),
+                error => Err(Box::new(error)),
+            },
+        }
+    }
+
+    async fn presign(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .presigned(PresigningConfig::expires_in(expires_in)?)
+            .await?;
+
+        Ok(Some(request.uri().to_string()))
+    }
+}
+
+fn content_type_for(key: &str) -> &'static str {
+    if key.ends_with(".wav") {
+        "audio/wav"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// `x-amz-copy-source` value: `bucket/key` with the key percent-encoded.
+fn copy_source(bucket: &str, key: &str) -> String {
+    format!("{bucket}/{}", percent_encode(key))
+}
+
+/// User metadata travels in HTTP headers, so values are percent-encoded to
+/// keep them ASCII.
+/// The whole-object digest is kept in user metadata as well, because
+/// multipart uploads only carry per-part checksums.
+pub fn user_metadata(metadata: &ObjectMetadata) -> HashMap<String, String> {
+    let mut user: HashMap<String, String> = metadata
+        .user
+        .iter()
+        .map(|(name, value)| (name.clone(), percent_encode(value)))
+        .collect();
+    if let Some(digest) = metadata.sha256 {
+        user.insert("sha256".into(), to_hex(&digest));
+    }
+    user
+}
+
+/// `Range` header for a half-open byte range; `None` for the whole object.
+fn range_header(range: &Range<u64>) -> Option<String> {
+    match (range.start, range.end) {
+        (0, u64::MAX) => None,
+        (start, u64::MAX) => Some(format!("bytes={start}-")),
+        (start, end) => Some(format!(
+            "bytes={start}-{}",
+            end.saturating_sub(1).max(start)
+        )),
+    }
+}
+
+/// Maps an S3 failure onto an `io::ErrorKind` so the retry policy can tell a
+/// missing object from a storage outage.
+pub fn object_error<E>(
+    operation: &str,
+    bucket: &str,
+    key: &str,
+    error: SdkError<E, HttpResponse>,
+) -> io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let kind = match error
+        .raw_response()
+        .map(|response| response.status().as_u16())
+    {
+        Some(404) => io::ErrorKind::NotFound,
+        Some(401 | 403) => io::ErrorKind::PermissionDenied,
+        Some(416) => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+
+    io::Error::new(
+        kind,
+        format!(
+            "{operation} failed for s3://{bucket}/{key}: {}",
+            DisplayErrorContext(&error)
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_source, range_header};
+
+    #[test]
+    fn builds_range_headers() {
+        assert_eq!(range_header(&(0..u64::MAX)), None);
+        assert_eq!(range_header(&(10..u64::MAX)), Some("bytes=10-".into()));
+        assert_eq!(range_header(&(0..1024)), Some("bytes=0-1023".into()));
+    }
+
+    #[test]
+    fn encodes_copy_sources() {
+        assert_eq!(
+            copy_source("out", "cache/a b+c.wav"),
+            "out/cache/a%20b%2Bc.wav"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use async_trait::async_trait;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+use super::{ObjectChunk, ObjectMetadata, StorageBackend, StoredObject};
+use crate::lib::error::WorkerError;
+
+/// Stands in for a backend whose configuration is broken. The worker keeps
+/// consuming, and every job that needs storage fails with the configuration
+/// error instead of the whole process exiting.
+pub struct UnavailableBackend {
+    reason: String,
+}
+
+impl UnavailableBackend {
+    pub fn new(reason: String) -> Self {
+        Self { reason }
+    }
+
+    fn error(&self) -> Box<dyn std::error::Error + Send + Sync> {
+        Box::new(WorkerError::Config(self.reason.clone()))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for UnavailableBackend {
+    async fn put(
+        &self,
+        _key: &str,
+        _source: &Path,
+        _metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>> {
+        Err(self.error())
+    }
+
+    async fn get(
+        &self,
+        _key: &str,
+        _range: Range<u64>,
+    ) -> Result<ObjectChunk, Box<dyn std::error::Error + Send + Sync>> {
+        Err(self.error())
+    }
+
+    async fn copy(
+        &self,
+        _from: &str,
+        _to: &str,
+        _metadata: &ObjectMetadata,
+    ) -> Result<StoredObject, Box<dyn std::error::Error + Send + Sync>> {
+        Err(self.error())
+    }
+
+    async fn delete(&self, _key: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Err(self.error())
+    }
+
+    async fn exists(&self, _key: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        Err(self.error())
+    }
+
+    async fn presign(
+        &self,
+        _key: &str,
+        _expires_in: Duration,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        Err(self.error())
+    }
+}
@@ -0,0 +1,246 @@
+use futures_lite::stream::StreamExt;
+use lapin::Consumer;
+use lapin::message::Delivery;
+use lapin::options::BasicCancelOptions;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+use tokio::task::JoinSet;
+
+use crate::lib::broker::{Backoff, BrokerSession};
+use crate::lib::cancellation::{CancelReason, JobRegistry};
+use crate::lib::config::WorkerConfig;
+use crate::lib::control::handle_control_delivery;
+use crate::lib::job_handler::{JobContext, handle_delivery};
+use crate::lib::shutdown::{drain_jobs, shutdown_signal};
+use crate::lib::storage::StorageBackend;
+
+enum SessionEnd {
+    Shutdown,
+    ConnectionLost(String),
+}
+
+/// Keeps the worker connected: (re)connects with backoff, consumes until the
+/// connection drops, and repeats until a shutdown signal arrives.
+pub async fn run(config: &WorkerConfig) {
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    let mut backoff = Backoff::new();
+    // Outlives individual connections so cancellations received before a
+    // reconnect still apply to jobs picked up after it.
+    let registry = JobRegistry::new();
+    let storage = config.storage.build();
+
+    loop {
+        let opened = tokio::select! {
+            _ = &mut shutdown => {
+                println!("Shutdown signal received while disconnected");
+                return;
+            }
+            opened = BrokerSession::open(config) => opened,
+        };
+
+        let session = match opened {
+            Ok(session) => session,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                eprintln!(
+                    "Failed to connect to RabbitMQ: {}. Retrying in {:?}",
+                    e, delay
+                );
+                tokio::select! {
+                    _ = &mut shutdown => {
+                        println!("Shutdown signal received while disconnected");
+                        return;
+                    }
+                    _ = tokio::time::sleep(delay) => continue,
+                }
+            }
+        };
+        backoff.reset();
+
+        let queues: Vec<String> = config
+            .queues
+            .iter()
+            .map(|queue| format!("{} x{}", queue.name, queue.concurrency))
+            .collect();
+        println!(
+            " [*] Connected. Waiting for messages on {}. To exit send SIGTERM or press CTRL+C",
+            queues.join(", ")
+        );
+
+        match consume(config, session, &registry, &storage, &mut shutdown).await {
+            SessionEnd::Shutdown => return,
+            SessionEnd::ConnectionLost(reason) => {
+                eprintln!("Lost RabbitMQ connection ({}), reconnecting...", reason);
+            }
+        }
+    }
+}
+
+/// A delivery together with the slot it will run in.
+struct Incoming {
+    ctx: JobContext,
+    delivery: Delivery,
+    permit: OwnedSemaphorePermit,
+}
+
+async fn consume<F>(
+    config: &WorkerConfig,
+    mut session: BrokerSession,
+    registry: &Arc<JobRegistry>,
+    storage: &Arc<dyn StorageBackend>,
+    shutdown: &mut Pin<&mut F>,
+) -> SessionEnd
+where
+    F: Future<Output = ()>,
+{
+    let mut in_flight = JoinSet::new();
+    let (incoming_tx, mut incoming_rx) = mpsc::channel::<Result<Incoming, String>>(1);
+
+    // One forwarder per queue, each waiting on its own slots before taking the
+    // next delivery, so a full queue never blocks the others.
+    let inputs = Arc::new(config.inputs.clone());
+    let mut forwarders = JoinSet::new();
+    for queue in &session.queues {
+        let ctx = JobContext {
+            channel: queue.channel.clone(),
+            queue: queue.queue.name.clone(),
+            registry: Arc::clone(registry),
+            retry_policy: config.retry_policy.clone(),
+            limits: config.limits,
+            inputs: Arc::clone(&inputs),
+            storage: Arc::clone(storage),
+            output_cache: config.output_cache,
+            output_url_ttl: config.output_url_ttl,
+            scratch_dir: config.scratch_dir.clone(),
+            marker_root: config.marker_root.clone(),
+            verify_outputs: config.verify_outputs,
+        };
+        forwarders.spawn(forward_deliveries(
+            ctx,
+            queue.consumer.clone(),
+            Arc::new(Semaphore::new(queue.queue.concurrency)),
+            incoming_tx.clone(),
+        ));
+    }
+    drop(incoming_tx);
+
+    let end = loop {
+        tokio::select! {
+            _ = shutdown.as_mut() => {
+                println!("Shutdown signal received, no longer accepting jobs");
+                break SessionEnd::Shutdown;
+            }
+            Some(_) = in_flight.join_next(), if !in_flight.is_empty() => {}
+            control = session.control.next() => match control {
+                Some(Ok(delivery)) => handle_control_delivery(registry, &delivery),
+                Some(Err(e)) => break SessionEnd::ConnectionLost(e.to_string()),
+                None => break SessionEnd::ConnectionLost("control stream ended".into()),
+            },
+            incoming = incoming_rx.recv() => {
+                let Incoming { ctx, delivery, permit } = match incoming {
+                    Some(Ok(incoming)) => incoming,
+                    Some(Err(reason)) => break SessionEnd::ConnectionLost(reason),
+                    None => break SessionEnd::ConnectionLost("all consumers stopped".into()),
+                };
+
+                in_flight.spawn(async move {
+                    if let Err(e) = handle_delivery(&ctx, delivery).await {
+                        eprintln!("Error handling delivery: {}", e);
+                    }
+                    drop(permit);
+                });
+            }
+        }
+    };
+
+    forwarders.abort_all();
+
+    match end {
+        SessionEnd::Shutdown => {
+            for queue in &session.queues {
+                if let Err(e) = queue
+                    .channel
+                    .basic_cancel(&config.consumer_tag, BasicCancelOptions::default())
+                    .await
+                {
+                    eprintln!("Failed to cancel consumer on {}: {}", queue.queue.name, e);
+                }
+            }
+
+            drain_with_control(
+                &mut in_flight,
+                registry,
+                &mut session.control,
+                config.shutdown_grace_period,
+            )
+            .await;
+            session.close().await;
+            println!("Worker stopped");
+        }
+        SessionEnd::ConnectionLost(_) => {
+            // The broker has already requeued every unacked delivery, so there
+            // is no point finishing work we can no longer ack.
+            registry.cancel_all(CancelReason::Shutdown);
+            drain_jobs(&mut in_flight, registry, Duration::ZERO).await;
+        }
+    }
+
+    end
+}
+
+/// Drains in-flight jobs while still applying control messages, so a job
+/// cancelled during shutdown stops instead of running out the grace period.
+async fn drain_with_control(
+    in_flight: &mut JoinSet<()>,
+    registry: &Arc<JobRegistry>,
+    control: &mut Consumer,
+    grace_period: Duration,
+) {
+    let drain = drain_jobs(in_flight, registry, grace_period);
+    tokio::pin!(drain);
+    let mut control_open = true;
+    loop {
+        tokio::select! {
+            _ = &mut drain => return,
+            message = control.next(), if control_open => match message {
+                Some(Ok(delivery)) => handle_control_delivery(registry, &delivery),
+                // Jobs still drain; they just cannot be cancelled any more.
+                Some(Err(_)) | None => control_open = false,
+            },
+        }
+    }
+}
+
+async fn forward_deliveries(
+    ctx: JobContext,
+    mut consumer: Consumer,
+    slots: Arc<Semaphore>,
+    incoming: mpsc::Sender<Result<Incoming, String>>,
+) {
+    loop {
+        let permit = Arc::clone(&slots)
+            .acquire_owned()
+            .await
+            .expect("job slots are never closed");
+
+        let next = match consumer.next().await {
+            Some(Ok(delivery)) => Ok(Incoming {
+                ctx: ctx.clone(),
+                delivery,
+                permit,
+            }),
+            Some(Err(e)) => Err(e.to_string()),
+            None => Err(format!("consumer for {} ended", ctx.queue)),
+        };
+
+        let stream_ended = next.is_err();
+        if incoming.send(next).await.is_err() || stream_ended {
+            return;
+        }
+    }
+}
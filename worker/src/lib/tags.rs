@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use symphonia::core::meta::{MetadataRevision, StandardVisualKey, Value};
+
+use crate::lib::error::WorkerError;
+use crate::lib::riff::Chunk;
+
+/// Tags with a RIFF INFO equivalent. INFO is what most WAV tools read.
+const INFO_IDS: &[(&str, &[u8; 4])] = &[
+    ("track_title", b"INAM"),
+    ("artist", b"IART"),
+    ("album", b"IPRD"),
+    ("genre", b"IGNR"),
+    ("date", b"ICRD"),
+    ("comment", b"ICMT"),
+    ("copyright", b"ICOP"),
+    ("track_number", b"ITRK"),
+    ("engineer", b"IENG"),
+    ("encoder", b"ISFT"),
+];
+
+/// Tags with a dedicated ID3v2.4 text frame. Other tags become `TXXX` frames.
+const ID3_FRAMES: &[(&str, &[u8; 4])] = &[
+    ("track_title", b"TIT2"),
+    ("artist", b"TPE1"),
+    ("album", b"TALB"),
+    ("album_artist", b"TPE2"),
+    ("genre", b"TCON"),
+    ("date", b"TDRC"),
+    ("track_number", b"TRCK"),
+    ("disc_number", b"TPOS"),
+    ("composer", b"TCOM"),
+    ("copyright", b"TCOP"),
+    ("encoder", b"TSSE"),
+    ("bpm", b"TBPM"),
+    ("label", b"TPUB"),
+    ("ident_isrc", b"TSRC"),
+];
+
+/// ID3v2 sizes are 28-bit synchsafe integers.
+const ID3_MAX_SIZE: usize = (1 << 28) - 1;
+
+/// How a job changes the tags of its input, e.g.
+/// `{"set": {"artist": "Someone"}, "remove": ["comment"]}`. Keys are the
+/// names reported in the analysis: `track_title`, `artist`, `album`, ...
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MetadataEdit {
+    /// Carries the input's tags and artwork over to the output.
+    #[serde(default = "keep_by_default")]
+    pub keep: bool,
+    /// Tags to add, or to overwrite when the input has them.
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    pub remove_artwork: bool,
+}
+
+fn keep_by_default() -> bool {
+    true
+}
+
+impl Default for MetadataEdit {
+    fn default() -> Self {
+        Self {
+            keep: true,
+            set: BTreeMap::new(),
+            remove: Vec::new(),
+            remove_artwork: false,
+        }
+    }
+}
+
+impl MetadataEdit {
+    pub fn validate(&self) -> Result<(), WorkerError> {
+        for (key, value) in &self.set {
+            if key.is_empty() || !key.bytes().all(|b| b.is_ascii_graphic()) {
+                return Err(WorkerError::InvalidJob(format!(
+                    "metadata key {key:?} must be non-empty printable ASCII"
+                )));
+            }
+            if value.contains('\0') {
+                return Err(WorkerError::InvalidJob(format!(
+                    "metadata value of {key} contains a NUL byte"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Embedded picture, usually the front cover.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artwork {
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Artwork as reported in the analysis, without its bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArtworkInfo {
+    pub media_type: String,
+    pub size_bytes: u64,
+}
+
+/// Tags of a file, keyed by snake_case name, and its artwork.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MediaTags {
+    pub tags: BTreeMap<String, String>,
+    pub artwork: Option<Artwork>,
+}
+
+impl MediaTags {
+    /// Merges metadata revisions, e.g. an ID3 tag in front of the stream and
+    /// the container's own tags. Later revisions win.
+    pub fn from_revisions<'a>(revisions: impl IntoIterator<Item = &'a MetadataRevision>) -> Self {
+        let mut merged = Self::default();
+        for revision in revisions {
+            for tag in revision.tags() {
+                let value = match &tag.value {
+                    Value::Binary(_) | Value::Flag => continue,
+                    value => value.to_string(),
+                };
+                let value = value.trim_end_matches('\0').trim();
+                if value.is_empty() {
+                    continue;
+                }
+                let key = match tag.std_key {
+                    Some(std_key) => snake_case(&format!("{std_key:?}")),
+                    None => {
+                        let key = tag.key.to_lowercase();
+                        key.strip_prefix("txxx:").map(str::to_string).unwrap_or(key)
+                    }
+                };
+                merged.tags.insert(key, value.to_string());
+            }
+
+            let visuals = revision.visuals();
+            let cover = visuals
+                .iter()
+                .find(|v| v.usage == Some(StandardVisualKey::FrontCover))
+                .or_else(|| visuals.first());
+            if let Some(cover) = cover {
+                merged.artwork = Some(Artwork {
+                    media_type: cover.media_type.clone(),
+                    data: cover.data.to_vec(),
+                });
+            }
+        }
+        merged
+    }
+
+    /// The tags the output gets when `edit` is applied to these.
+    pub fn edited(&self, edit: &MetadataEdit) -> Self {
+        let mut edited = if edit.keep {
+            self.clone()
+        } else {
+            Self::default()
+        };
+        for key in &edit.remove {
+            edited.tags.remove(key);
+        }
+        if edit.remove_artwork {
+            edited.artwork = None;
+        }
+        edited.tags.extend(edit.set.clone());
+        edited
+    }
+
+    pub fn artwork_info(&self) -> Option<ArtworkInfo> {
+        self.artwork.as_ref().map(|artwork| ArtworkInfo {
+            media_type: artwork.media_type.clone(),
+            size_bytes: artwork.data.len() as u64,
+        })
+    }
+
+    /// A `LIST INFO` chunk with the tags INFO has fields for, and an `id3 `
+    /// chunk with all tags and the artwork. Nothing when there is nothing to
+    /// write.
+    pub fn wav_chunks(&self) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+
+        let info: Vec<Chunk> = INFO_IDS
+            .iter()
+            .filter_map(|(key, id)| {
+                let value = self.tags.get(*key)?;
+                let mut data = value.as_bytes().to_vec();
+                data.push(0);
+                Some(Chunk::new(id, data))
+            })
+            .collect();
+        if !info.is_empty() {
+            chunks.push(Chunk::list(b"INFO", &info));
+        }
+
+        if !self.tags.is_empty() || self.artwork.is_some() {
+            chunks.push(Chunk::new(b"id3 ", self.id3_tag()));
+        }
+        chunks
+    }
+
+    /// An ID3v2.4 tag with UTF-8 text frames.
+    fn id3_tag(&self) -> Vec<u8> {
+        let mut frames = Vec::new();
+        for (key, value) in &self.tags {
+            let mut body = vec![3];
+            let id = match ID3_FRAMES.iter().find(|(name, _)| name == key) {
+                Some((_, id)) => *id,
+                None if key == "comment" => {
+                    body.extend_from_slice(b"XXX\0");
+                    b"COMM"
+                }
+                None => {
+                    body.extend_from_slice(key.as_bytes());
+                    body.push(0);
+                    b"TXXX"
+                }
+            };
+            body.extend_from_slice(value.as_bytes());
+            push_id3_frame(&mut frames, id, &body);
+        }
+
+        if let Some(artwork) = &self.artwork {
+            let mut body = vec![3];
+            body.extend_from_slice(artwork.media_type.as_bytes());
+            // Terminates the media type, then front cover, empty description.
+            body.extend_from_slice(&[0, 3, 0]);
+            body.extend_from_slice(&artwork.data);
+            push_id3_frame(&mut frames, b"APIC", &body);
+        }
+
+        let mut tag = b"ID3\x04\x00\x00".to_vec();
+        tag.extend_from_slice(&synchsafe(frames.len()));
+        tag.extend_from_slice(&frames);
+        tag
+    }
+}
+
+/// Frames too large for ID3 are left out rather than failing the job.
+fn push_id3_frame(frames: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    if frames.len() + body.len() + 10 > ID3_MAX_SIZE {
+        eprintln!(
+            "Leaving out ID3 frame {} of {} bytes",
+            String::from_utf8_lossy(id),
+            body.len()
+        );
+        return;
+    }
+    frames.extend_from_slice(id);
+    frames.extend_from_slice(&synchsafe(body.len()));
+    frames.extend_from_slice(&[0, 0]);
+    frames.extend_from_slice(body);
+}
+
+fn synchsafe(size: usize) -> [u8; 4] {
+    [
+        (size >> 21 & 0x7f) as u8,
+        (size >> 14 & 0x7f) as u8,
+        (size >> 7 & 0x7f) as u8,
+        (size & 0x7f) as u8,
+    ]
+}
+
+/// `AlbumArtist` becomes `album_artist`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MediaTags, MetadataEdit};
+    use symphonia::core::meta::{MetadataBuilder, StandardTagKey, StandardVisualKey, Tag, Visual};
+
+    fn revision(title: &str) -> symphonia::core::meta::MetadataRevision {
+        let mut builder = MetadataBuilder::new();
+        builder
+            .add_tag(Tag::new(
+                Some(StandardTagKey::TrackTitle),
+                "TIT2",
+                title.into(),
+            ))
+            .add_tag(Tag::new(
+                Some(StandardTagKey::AlbumArtist),
+                "TPE2",
+                "Band\0".into(),
+            ))
+            .add_tag(Tag::new(None, "TXXX:Studio", "B".into()))
+            .add_visual(Visual {
+                media_type: "image/png".into(),
+                dimensions: None,
+                bits_per_pixel: None,
+                color_mode: None,
+                usage: Some(StandardVisualKey::FrontCover),
+                tags: Vec::new(),
+                data: Box::new([1, 2, 3]),
+            });
+        builder.metadata()
+    }
+
+    #[test]
+    fn reads_and_edits_tags() {
+        let input = MediaTags::from_revisions([&revision("Old"), &revision("New")]);
+        assert_eq!(input.tags["track_title"], "New");
+        assert_eq!(input.tags["album_artist"], "Band");
+        assert_eq!(input.tags["studio"], "B");
+        assert_eq!(input.artwork_info().map(|a| a.size_bytes), Some(3));
+
+        let edit: MetadataEdit = serde_json::from_str(
+            r#"{"set": {"artist": "Someone"}, "remove": ["studio"], "remove_artwork": true}"#,
+        )
+        .expect("edit should parse");
+        let output = input.edited(&edit);
+        assert!(edit.keep);
+        assert_eq!(output.tags["artist"], "Someone");
+        assert_eq!(output.tags["track_title"], "New");
+        assert!(!output.tags.contains_key("studio"));
+        assert_eq!(output.artwork, None);
+
+        let stripped = input.edited(&MetadataEdit {
+            keep: false,
+            ..MetadataEdit::default()
+        });
+        assert_eq!(stripped, MediaTags::default());
+        assert!(stripped.wav_chunks().is_empty());
+    }
+
+    #[test]
+    fn writes_info_and_id3_chunks() {
+        let tags = MediaTags::from_revisions([&revision("Song")]);
+        let chunks = tags.wav_chunks();
+
+        assert_eq!(&chunks[0].id, b"LIST");
+        assert!(chunks[0].data.starts_with(b"INFO"));
+        assert!(chunks[0].data.windows(9).any(|w| w == b"INAM\x05\0\0\0S"));
+        assert_eq!(&chunks[1].id, b"id3 ");
+        let id3 = &chunks[1].data;
+        assert!(id3.starts_with(b"ID3\x04"));
+        assert_eq!(
+            id3.len(),
+            10 + usize::from(id3[9]) + (usize::from(id3[8]) << 7)
+        );
+        assert!(id3.windows(4).any(|w| w == b"TIT2"));
+        assert!(id3.windows(4).any(|w| w == b"APIC"));
+    }
+
+    #[test]
+    fn rejects_unwritable_keys() {
+        let mut edit = MetadataEdit::default();
+        edit.set.insert("my key".into(), "value".into());
+
+        assert_eq!(edit.validate().map_err(|e| e.code()), Err("invalid_job"));
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use symphonia::core::codecs::CODEC_TYPE_NULL;
+use symphonia::core::formats::Track;
+
+use crate::lib::error::WorkerError;
+
+/// Picks one audio track of a container, e.g. `{"language": "eng"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackSelector {
+    /// Position among the audio tracks, starting at 0.
+    Index(usize),
+    /// Language as tagged in the container, e.g. `eng` or `de`.
+    Language(String),
+    /// Id the container assigned to the track.
+    Id(u32),
+}
+
+/// The tracks a job renders.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tracks {
+    /// The selected track, or the first audio track.
+    One(Option<TrackSelector>),
+    /// Every audio track, each into its own output.
+    All,
+}
+
+impl Tracks {
+    /// Where track `index` is written when the job's output is `path`.
+    /// Rendering every track adds the index to each file name:
+    /// `processed/job-1.wav` becomes `processed/job-1.track1.wav`.
+    pub fn output_path(&self, path: &Path, index: usize) -> PathBuf {
+        if *self != Tracks::All {
+            return path.to_path_buf();
+        }
+
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(extension) => format!("{stem}.track{index}.{}", extension.to_string_lossy()),
+            None => format!("{stem}.track{index}"),
+        };
+        path.with_file_name(name)
+    }
+
+    /// The tracks to decode with their position among the audio tracks, in
+    /// container order.
+    pub fn select<'a>(&self, tracks: &'a [Track]) -> Result<Vec<(usize, &'a Track)>, WorkerError> {
+        let audio: Vec<(usize, &Track)> = tracks
+            .iter()
+            .filter(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .enumerate()
+            .collect();
+        if audio.is_empty() {
+            return Err(WorkerError::UnsupportedCodec(
+                "input has no audio track".into(),
+            ));
+        }
+
+        let selector = match self {
+            Tracks::All => return Ok(audio),
+            Tracks::One(None) => return Ok(vec![audio[0]]),
+            Tracks::One(Some(selector)) => selector,
+        };
+        let found = audio.into_iter().find(|(index, track)| match selector {
+            TrackSelector::Index(wanted) => index == wanted,
+            TrackSelector::Language(language) => track
+                .language
+                .as_deref()
+                .is_some_and(|tagged| tagged.eq_ignore_ascii_case(language)),
+            TrackSelector::Id(id) => track.id == *id,
+        });
+
+        found.map(|selected| vec![selected]).ok_or_else(|| {
+            WorkerError::InvalidJob(format!("input has no audio track matching {selector:?}"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TrackSelector, Tracks};
+    use std::path::Path;
+    use symphonia::core::codecs::{CODEC_TYPE_PCM_S16LE, CodecParameters};
+    use symphonia::core::formats::Track;
+
+    fn track(id: u32, language: Option<&str>, audio: bool) -> Track {
+        let mut params = CodecParameters::new();
+        if audio {
+            params.for_codec(CODEC_TYPE_PCM_S16LE);
+        }
+        Track {
+            id,
+            codec_params: params,
+            language: language.map(str::to_string),
+        }
+    }
+
+    fn ids(selected: &[(usize, &Track)]) -> Vec<u32> {
+        selected.iter().map(|(_, track)| track.id).collect()
+    }
+
+    #[test]
+    fn selects_audio_tracks_by_index_language_or_id() {
+        let tracks = [
+            track(1, None, false),
+            track(2, Some("eng"), true),
+            track(3, Some("deu"), true),
+        ];
+        let select = |selector| {
+            Tracks::One(Some(selector))
+                .select(&tracks)
+                .map(|selected| ids(&selected))
+        };
+
+        assert_eq!(
+            ids(&Tracks::One(None)
+                .select(&tracks)
+                .expect("track should be selected")),
+            vec![2]
+        );
+        assert_eq!(
+            select(TrackSelector::Index(1)).expect("track should be selected"),
+            vec![3]
+        );
+        assert_eq!(
+            select(TrackSelector::Language("DEU".into())).expect("track should be selected"),
+            vec![3]
+        );
+        assert_eq!(
+            select(TrackSelector::Id(2)).expect("track should be selected"),
+            vec![2]
+        );
+        assert_eq!(
+            ids(&Tracks::All
+                .select(&tracks)
+                .expect("track should be selected")),
+            vec![2, 3]
+        );
+        assert_eq!(
+            select(TrackSelector::Id(1)).map_err(|e| e.code()),
+            Err("invalid_job")
+        );
+    }
+
+    #[test]
+    fn names_one_output_per_track() {
+        let path = Path::new("processed/job-1.wav");
+
+        assert_eq!(Tracks::One(None).output_path(path, 1), path);
+        assert_eq!(
+            Tracks::All.output_path(path, 1),
+            Path::new("processed/job-1.track1.wav")
+        );
+    }
+}
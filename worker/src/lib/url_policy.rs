@@ -0,0 +1,431 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Response, Url, redirect};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Content types accepted from remote inputs besides `audio/*` and `video/*`.
+const GENERIC_CONTENT_TYPES: &[&str] = &[
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/ogg",
+];
+
+/// Decides which remote input URLs the worker may fetch. Input URLs come from
+/// users through the API, so without this anyone could make the worker read
+/// cloud metadata endpoints or services on the internal network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlPolicy {
+    /// Hosts (`cdn.example.com`, `*.example.com`) or URL prefixes
+    /// (`https://bucket.example.com/uploads/`, matched on the origin and whole
+    /// path segments) inputs must match. Empty allows
+    /// any host that passes the address checks.
+    pub allowlist: Vec<String>,
+    /// Buckets (`uploads`) or bucket prefixes (`uploads/inputs/`) that
+    /// `s3://` and `r2://` inputs may be read from. Those are read with the
+    /// worker's own credentials, so with an empty list none are allowed.
+    pub object_allowlist: Vec<String>,
+    /// Local input paths must resolve to a file below this directory, where
+    /// the API stores uploads. Nothing else on the worker's disk is an input.
+    pub local_root: PathBuf,
+    /// Skips the private, loopback and link-local address checks. Only meant
+    /// for local development against services on the same machine.
+    pub allow_private_networks: bool,
+    pub max_redirects: usize,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+}
+
+impl UrlPolicy {
+    /// Parses a user-supplied input URL and checks it against the policy.
+    pub fn parse_url(&self, raw: &str) -> Result<Url, InputRejected> {
+        let url = Url::parse(raw).map_err(|e| InputRejected::new(format!("invalid URL: {e}")))?;
+        self.check_url(&url)?;
+        Ok(url)
+    }
+
+    /// Checks the parts of `url` that can be judged without resolving it. The
+    /// resolved addresses are checked by the client from `client()`.
+    pub fn check_url(&self, url: &Url) -> Result<(), InputRejected> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(InputRejected::new(format!(
+                "unsupported URL scheme {}",
+                url.scheme()
+            )));
+        }
+
+        let Some(host) = url.host_str() else {
+            return Err(InputRejected::new("URL has no host"));
+        };
+
+        if !self.allowlist.is_empty()
+            && !self
+                .allowlist
+                .iter()
+                .any(|entry| allowlist_matches(entry, host, url))
+        {
+            return Err(InputRejected::new(format!("host {host} is not allowed")));
+        }
+
+        // IP literals never reach the resolver, so they are checked here.
+        let literal = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>();
+        if let Ok(ip) = literal
+            && !self.allow_private_networks
+            && !is_public(ip)
+        {
+            return Err(InputRejected::new(format!(
+                "address {ip} is not publicly routable"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Resolves a local input path and checks it lies below `local_root`.
+    /// Symlinks and `..` are resolved first, so neither can escape it.
+    pub fn check_path(&self, path: &Path) -> Result<PathBuf, InputRejected> {
+        let rejected = || {
+            InputRejected::new(format!(
+                "local path {} is not a file below the input root",
+                path.display()
+            ))
+        };
+        let root = self.local_root.canonicalize().map_err(|_| rejected())?;
+        let resolved = path.canonicalize().map_err(|_| rejected())?;
+        if !resolved.starts_with(&root) {
+            return Err(rejected());
+        }
+        Ok(resolved)
+    }
+
+    /// Checks an object storage input against `object_allowlist`.
+    pub fn check_object(&self, bucket: &str, key: &str) -> Result<(), InputRejected> {
+        let allowed = self.object_allowlist.iter().any(|entry| {
+            let (allowed_bucket, prefix) = entry.split_once('/').unwrap_or((entry, ""));
+            allowed_bucket == bucket && path_prefix_matches(prefix, key)
+        });
+        if !allowed {
+            return Err(InputRejected::new(format!(
+                "object {bucket}/{key} is not in an allowed bucket or prefix"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Rejects responses that are obviously not media, e.g. an HTML login page
+    /// served instead of the file. A missing `Content-Type` is let through.
+    pub fn check_content_type(&self, response: &Response) -> Result<(), InputRejected> {
+        let Some(content_type) = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Ok(());
+        };
+
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if mime.starts_with("audio/")
+            || mime.starts_with("video/")
+            || GENERIC_CONTENT_TYPES.contains(&mime.as_str())
+        {
+            return Ok(());
+        }
+
+        Err(InputRejected::new(format!(
+            "unsupported content type {content_type}"
+        )))
+    }
+
+    /// A client that enforces this policy on every connection and redirect hop.
+    pub fn client(&self) -> reqwest::Result<Client> {
+        let redirect_policy = self.clone();
+        let mut builder = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout)
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() > redirect_policy.max_redirects {
+                    let error = InputRejected::new(format!(
+                        "more than {} redirects",
+                        redirect_policy.max_redirects
+                    ));
+                    return attempt.error(error);
+                }
+                match redirect_policy.check_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(error) => attempt.error(error),
+                }
+            }));
+
+        if !self.allow_private_networks {
+            builder = builder.dns_resolver(Arc::new(PublicOnlyResolver));
+        }
+
+        builder.build()
+    }
+}
+
+/// Whether `prefix` covers `path`, ending on a `/` boundary: `inputs`
+/// covers `inputs/a.wav` but not `inputs-old/a.wav`. Empty covers everything.
+fn path_prefix_matches(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => {
+            prefix.is_empty() || prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+        }
+        None => false,
+    }
+}
+
+fn allowlist_matches(entry: &str, host: &str, url: &Url) -> bool {
+    if entry.contains("://") {
+        // Same origin, then a path on a `/` boundary, so neither
+        // `https://cdn.example.com.evil.net` nor `/uploads-old` slips through.
+        return Url::parse(entry).is_ok_and(|entry| {
+            entry.origin() == url.origin() && path_prefix_matches(entry.path(), url.path())
+        });
+    }
+
+    let host = host.to_ascii_lowercase();
+    let entry = entry.to_ascii_lowercase();
+    match entry.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.')),
+        None => host == entry,
+    }
+}
+
+/// Resolves through the system resolver and refuses hosts with any address
+/// that is not publicly routable. The connection then uses exactly these
+/// addresses, so a second, different DNS answer cannot sneak past the check.
+struct PublicOnlyResolver;
+
+impl Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+
+            if let Some(blocked) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                return Err(InputRejected::new(format!(
+                    "host {host} resolves to {} which is not publicly routable",
+                    blocked.ip()
+                ))
+                .into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(embedded) => is_public_v4(embedded),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+/// The IPv4 address an IPv6 address routes to: IPv4-mapped and
+/// IPv4-compatible (`::/96`), NAT64 (`64:ff9b::/96`) and 6to4 (`2002::/16`).
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let ipv4 = |high: u16, low: u16| Ipv4Addr::from(u32::from(high) << 16 | u32::from(low));
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0 | 0xffff, high, low] | [0x64, 0xff9b, 0, 0, 0, 0, high, low] => {
+            Some(ipv4(high, low))
+        }
+        [0x2002, high, low, ..] => Some(ipv4(high, low)),
+        _ => None,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8, 100.64.0.0/10 (carrier-grade NAT), 240.0.0.0/4 (reserved)
+        || first == 0
+        || (first == 100 && (second & 0xc0) == 64)
+        || first >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // 64:ff9b:1::/48, NAT64 for local use
+        || ip.segments()[..3] == [0x64, 0xff9b, 1])
+}
+
+/// A remote input was refused by the `UrlPolicy`. Retrying cannot help.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputRejected(String);
+
+impl InputRejected {
+    fn new(reason: impl Into<String>) -> Self {
+        Self(reason.into())
+    }
+}
+
+impl fmt::Display for InputRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "input URL rejected: {}", self.0)
+    }
+}
+
+impl Error for InputRejected {}
+
+#[cfg(test)]
+mod tests {
+    use super::{UrlPolicy, is_public};
+    use crate::lib::retry::find_cause;
+    use reqwest::Url;
+    use std::time::Duration;
+
+    fn policy(allowlist: &[&str]) -> UrlPolicy {
+        UrlPolicy {
+            allowlist: allowlist.iter().map(|entry| entry.to_string()).collect(),
+            object_allowlist: vec!["uploads/inputs/".into(), "shared".into()],
+            local_root: std::env::temp_dir(),
+            allow_private_networks: false,
+            max_redirects: 3,
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn url(raw: &str) -> Url {
+        Url::parse(raw).expect("test URL should parse")
+    }
+
+    #[test]
+    fn blocks_private_and_link_local_literals() {
+        let policy = policy(&[]);
+
+        for blocked in [
+            "http://169.254.169.254/latest/meta-data/",
+            "http://127.0.0.1:15672/",
+            "http://10.0.0.5/audio.wav",
+            "http://[::1]/audio.wav",
+            "http://[::ffff:192.168.1.1]/audio.wav",
+            "http://[::a9fe:a9fe]/audio.wav",
+            "http://[64:ff9b::a9fe:a9fe]/latest/meta-data/",
+            "http://[2002:7f00:1::]/audio.wav",
+            "http://[64:ff9b:1::a00:5]/audio.wav",
+            "file:///etc/passwd",
+        ] {
+            assert!(policy.check_url(&url(blocked)).is_err(), "{blocked}");
+        }
+        assert!(policy.check_url(&url("https://8.8.8.8/audio.wav")).is_ok());
+        assert!(
+            policy
+                .check_url(&url("https://[64:ff9b::808:808]/"))
+                .is_ok()
+        );
+        assert!(policy.check_url(&url("https://[2002:808:808::1]/")).is_ok());
+        assert!(!is_public("100.64.0.1".parse().expect("valid ip")));
+    }
+
+    #[test]
+    fn applies_host_and_prefix_allowlists() {
+        let policy = policy(&[
+            "*.example.com",
+            "https://cdn.test/uploads/",
+            "https://media.test",
+            "https://files.test/audio",
+        ]);
+
+        assert!(
+            policy
+                .check_url(&url("https://a.example.com/x.wav"))
+                .is_ok()
+        );
+        assert!(policy.check_url(&url("https://example.com/x.wav")).is_err());
+        assert!(
+            policy
+                .check_url(&url("https://evilexample.com/x.wav"))
+                .is_err()
+        );
+        assert!(
+            policy
+                .check_url(&url("https://cdn.test/uploads/x.wav"))
+                .is_ok()
+        );
+        assert!(
+            policy
+                .check_url(&url("https://cdn.test/private/x.wav"))
+                .is_err()
+        );
+        assert!(policy.check_url(&url("https://media.test/x.wav")).is_ok());
+        for blocked in [
+            "https://media.test.evil.net/x.wav",
+            "https://media.test@evil.net/x.wav",
+            "https://media.test:8443/x.wav",
+            "http://media.test/x.wav",
+            "https://files.test/audio-old/x.wav",
+        ] {
+            assert!(policy.check_url(&url(blocked)).is_err(), "{blocked}");
+        }
+        assert!(
+            policy
+                .check_url(&url("https://files.test/audio/x.wav"))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn applies_the_object_allowlist() {
+        let policy = policy(&[]);
+
+        assert!(policy.check_object("uploads", "inputs/take.wav").is_ok());
+        assert!(policy.check_object("shared", "any/take.wav").is_ok());
+        assert!(policy.check_object("uploads", "cache/abc.wav").is_err());
+        assert!(
+            policy
+                .check_object("uploads", "inputs-old/take.wav")
+                .is_err()
+        );
+        assert!(policy.check_object("outputs", "inputs/take.wav").is_err());
+
+        let closed = UrlPolicy {
+            object_allowlist: Vec::new(),
+            ..policy
+        };
+        assert!(closed.check_object("uploads", "inputs/take.wav").is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_hosts_resolving_to_private_addresses() {
+        let client = policy(&[]).client().expect("client should build");
+
+        let error = client
+            .get("http://localhost:9/audio.wav")
+            .send()
+            .await
+            .expect_err("localhost should be refused");
+
+        assert!(find_cause::<super::InputRejected>(&error).is_some());
+    }
+}